@@ -0,0 +1,82 @@
+//! Moves every entity every frame, once through `set_position` and once
+//! through `rebuild_from`.
+//!
+//! Run with `cargo bench --bench rebuild`.
+
+use spatial_hash::SpatialHash;
+use spatial_hash::vec2::Vec2;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ENTITIES: usize = 10_000;
+const FRAMES: usize = 20;
+const QUERIES: usize = 2_000;
+
+fn main() {
+    println!("{ENTITIES} entities, {FRAMES} frames, {QUERIES} queries per frame");
+    report("update", run(false));
+    report("rebuild", run(true));
+}
+
+fn run(rebuild: bool) -> [Duration; 2] {
+    let mut grid = SpatialHash::new(
+        Vec2::new(10.0, 10.0),
+        Vec2::new(0.0, 0.0),
+        Vec2::new(999.0, 999.0),
+    )
+    .unwrap();
+    let mut rng = Lcg(0x2545_f491);
+    let size = Vec2::new(2.0, 2.0);
+    let handles: Vec<_> = (0..ENTITIES)
+        .map(|_| grid.create(rng.point(), size.clone(), ()).unwrap())
+        .collect();
+
+    let mut moving = Duration::ZERO;
+    let mut query = Duration::ZERO;
+    for _ in 0..FRAMES {
+        let positions: Vec<_> = handles.iter().map(|_| rng.point()).collect();
+        let begin = Instant::now();
+        if rebuild {
+            let moves = handles
+                .iter()
+                .zip(positions)
+                .map(|(&handle, pos)| (handle, pos, size.clone()));
+            grid.rebuild_from(moves).unwrap();
+        } else {
+            for (&handle, pos) in handles.iter().zip(positions) {
+                grid.set_position(handle, pos).unwrap();
+            }
+        }
+        moving += begin.elapsed();
+
+        let begin = Instant::now();
+        for _ in 0..QUERIES {
+            let min = rng.point();
+            let max = &min + Vec2::new(30.0, 30.0);
+            black_box(grid.query_rect(min, max));
+        }
+        query += begin.elapsed();
+    }
+    [moving, query]
+}
+
+fn report(name: &str, [moving, query]: [Duration; 2]) {
+    println!("{name:>8}: move {moving:>10.2?}  query {query:>10.2?}");
+}
+
+/// Small deterministic generator, good enough for spreading entities.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> f32 {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn point(&mut self) -> Vec2 {
+        Vec2::new(self.next() * 960.0 + 10.0, self.next() * 960.0 + 10.0)
+    }
+}
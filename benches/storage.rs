@@ -0,0 +1,130 @@
+//! Compares the cell storages against each other.
+//!
+//! Run with `cargo bench --bench storage`.
+
+use spatial_hash::SpatialHash;
+use spatial_hash::storage::{BTreeStorage, CellStorage};
+use spatial_hash::vec2::Vec2;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ENTITIES: usize = 10_000;
+const FRAMES: usize = 20;
+const QUERIES: usize = 2_000;
+// a large grid holding only a handful of entities
+const SPARSE_SIDE: f32 = 1_000.0;
+const SPARSE_ENTITIES: usize = 5;
+
+fn main() {
+    let cell_size = Vec2::new(10.0, 10.0);
+    let start = Vec2::new(0.0, 0.0);
+    let end = Vec2::new(999.0, 999.0);
+    let sparse = SpatialHash::new(cell_size.clone(), start.clone(), end.clone()).unwrap();
+    let dense = SpatialHash::dense(cell_size.clone(), start.clone(), end.clone()).unwrap();
+    let sorted =
+        SpatialHash::<(), BTreeStorage>::with_storage(cell_size, start.clone(), end).unwrap();
+
+    println!("{ENTITIES} entities, {FRAMES} frames, {QUERIES} queries per frame");
+    let sparse_time = run(sparse);
+    let dense_time = run(dense);
+    let sorted_time = run(sorted);
+    report("hashmap", sparse_time);
+    report("dense", dense_time);
+    report("btree", sorted_time);
+
+    let cell_size = Vec2::new(1.0, 1.0);
+    let end = Vec2::new(SPARSE_SIDE - 1.0, SPARSE_SIDE - 1.0);
+    let sparse = SpatialHash::new(cell_size.clone(), start.clone(), end.clone()).unwrap();
+    let dense = SpatialHash::dense(cell_size.clone(), start.clone(), end.clone()).unwrap();
+    let sorted = SpatialHash::<(), BTreeStorage>::with_storage(cell_size, start, end).unwrap();
+
+    println!(
+        "{SPARSE_ENTITIES} entities in {SPARSE_SIDE}x{SPARSE_SIDE} cells, {QUERIES} queries of each kind"
+    );
+    report_sparse("hashmap", run_sparse(sparse));
+    report_sparse("dense", run_sparse(dense));
+    report_sparse("btree", run_sparse(sorted));
+}
+
+/// Inserts, then moves every entity and runs rectangle queries each frame.
+fn run<S: CellStorage>(mut grid: SpatialHash<(), S>) -> [Duration; 3] {
+    let mut rng = Lcg(0x2545_f491);
+    let begin = Instant::now();
+    let handles: Vec<_> = (0..ENTITIES)
+        .map(|_| grid.create(rng.point(), Vec2::new(2.0, 2.0), ()).unwrap())
+        .collect();
+    let create = begin.elapsed();
+
+    let mut update = Duration::ZERO;
+    let mut query = Duration::ZERO;
+    for _ in 0..FRAMES {
+        let begin = Instant::now();
+        for &handle in &handles {
+            grid.set_position(handle, rng.point()).unwrap();
+        }
+        update += begin.elapsed();
+
+        let begin = Instant::now();
+        for _ in 0..QUERIES {
+            let min = rng.point();
+            let max = &min + Vec2::new(30.0, 30.0);
+            black_box(grid.query_rect(min, max));
+        }
+        query += begin.elapsed();
+    }
+    [create, update, query]
+}
+
+/// Runs rectangle, radius and nearest queries on a nearly empty grid.
+fn run_sparse<S: CellStorage>(mut grid: SpatialHash<(), S>) -> [Duration; 3] {
+    let mut rng = Lcg(0x2545_f491);
+    let scale = SPARSE_SIDE / 1_000.0;
+    for _ in 0..SPARSE_ENTITIES {
+        let pos = rng.point().mul(scale);
+        grid.create(pos, Vec2::new(1.0, 1.0), ()).unwrap();
+    }
+    let points: Vec<_> = (0..QUERIES).map(|_| rng.point().mul(scale)).collect();
+
+    let begin = Instant::now();
+    for min in &points {
+        black_box(grid.query_rect(min.clone(), min.add(6.0)));
+    }
+    let rect = begin.elapsed();
+
+    let begin = Instant::now();
+    for center in &points {
+        black_box(grid.query_radius(center.clone(), 3.0));
+    }
+    let radius = begin.elapsed();
+
+    let begin = Instant::now();
+    for point in &points {
+        black_box(grid.k_nearest(point.clone(), 1));
+    }
+    [rect, radius, begin.elapsed()]
+}
+
+fn report_sparse(name: &str, [rect, radius, nearest]: [Duration; 3]) {
+    println!("{name:>8}: rect {rect:>10.2?}  radius {radius:>10.2?}  nearest {nearest:>10.2?}");
+}
+
+fn report(name: &str, [create, update, query]: [Duration; 3]) {
+    println!("{name:>8}: create {create:>10.2?}  update {update:>10.2?}  query {query:>10.2?}");
+}
+
+/// Small deterministic generator, good enough for spreading entities.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> f32 {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn point(&mut self) -> Vec2 {
+        Vec2::new(self.next() * 960.0 + 10.0, self.next() * 960.0 + 10.0)
+    }
+}
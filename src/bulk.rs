@@ -0,0 +1,357 @@
+use crate::error::Error;
+use crate::scalar::Scalar;
+use crate::storage::CellStorage;
+use crate::vec2::*;
+use crate::{
+    Cell, EntityHandle, EntityRecord, EntityView, Footprint, SpatialHash, bucket_position,
+    bucket_with_room, record_mut, swap_out,
+};
+use std::collections::{HashMap, HashSet};
+
+impl<T, S: CellStorage, N: Scalar> SpatialHash<T, S, N> {
+    /// Creates all entities in one go, touching every cell once. Returns one
+    /// result per item in the same order; rejected items do not stop the others.
+    pub fn create_many(
+        &mut self,
+        entities: impl IntoIterator<Item = (Vec2<N>, Vec2<N>, T)>,
+    ) -> Vec<anyhow::Result<EntityHandle, Error>> {
+        self.thaw();
+        let mut results = Vec::new();
+        let mut inserts = Vec::new();
+        for (pos, size, data) in entities {
+            let footprint = match self.footprint(&pos, &size) {
+                Ok(footprint) => footprint,
+                Err(err) => {
+                    results.push(Err(err));
+                    continue;
+                }
+            };
+            let handle = self.allocate_handle();
+            self.slots[handle.index as usize].entity = Some(EntityRecord {
+                view: EntityView { handle, pos, size },
+                data,
+                footprint,
+                positions: smallvec::smallvec![0; footprint.len()],
+                outside_at: 0,
+                visited: Default::default(),
+            });
+            self.push_cells(&footprint, handle.index, &mut inserts);
+            results.push(Ok(handle));
+        }
+        self.insert_grouped(&mut inserts);
+        results
+    }
+
+    /// Removes all entities in one go and hands their payloads back, one result
+    /// per id in the same order. Stale ids (or ids listed twice) fail on their own.
+    pub fn remove_many(
+        &mut self,
+        ids: impl IntoIterator<Item = EntityHandle>,
+    ) -> Vec<anyhow::Result<T, Error>> {
+        self.thaw();
+        let mut accepted = Vec::new();
+        let mut removing = HashSet::new();
+        let mut removals = Vec::new();
+        for id in ids {
+            let footprint = match self.record(id) {
+                Ok(record) if removing.insert(id.index) => record.footprint,
+                Ok(_) => {
+                    accepted.push(Err(Error::StaleHandle));
+                    continue;
+                }
+                Err(err) => {
+                    accepted.push(Err(err));
+                    continue;
+                }
+            };
+            self.push_cells(&footprint, id.index, &mut removals);
+            accepted.push(Ok(id));
+        }
+        self.remove_grouped(&mut removals);
+        accepted
+            .into_iter()
+            .map(|id| id.map(|id| self.free_handle(id)))
+            .collect()
+    }
+
+    /// Moves and resizes all entities in one go, one result per item in the same
+    /// order. Rejected items stay where they were. If an id shows up more than
+    /// once, its last accepted bounds win.
+    ///
+    /// Like [`SpatialHash::update`], only the cells an entity leaves or enters
+    /// are touched, and the work is counted in [`SpatialHash::update_stats`].
+    pub fn update_many(
+        &mut self,
+        entities: impl IntoIterator<Item = (EntityHandle, Vec2<N>, Vec2<N>)>,
+    ) -> Vec<anyhow::Result<(), Error>> {
+        self.thaw();
+        let mut results = Vec::new();
+        let mut moves: Vec<(u32, Vec2<N>, Vec2<N>, Footprint)> = Vec::new();
+        let mut moved = HashMap::new();
+        for (id, pos, size) in entities {
+            let res = self
+                .record(id)
+                .and_then(|_| self.footprint(&pos, &size))
+                .map(|footprint| match moved.get(&id.index) {
+                    Some(&i) => moves[i] = (id.index, pos, size, footprint),
+                    None => {
+                        moved.insert(id.index, moves.len());
+                        moves.push((id.index, pos, size, footprint));
+                    }
+                });
+            results.push(res);
+        }
+
+        let mut removals = Vec::new();
+        let mut inserts = Vec::new();
+        for (index, _, _, footprint) in &moves {
+            let old = self.record_at(*index).footprint;
+            if old == *footprint {
+                self.update_stats.unchanged += 1;
+                continue;
+            }
+            let left = removals.len();
+            self.push_cells_left(&old, footprint, *index, &mut removals);
+            self.update_stats.cells_left += count_cells(&removals[left..]);
+            let entered = inserts.len();
+            self.push_cells_left(footprint, &old, *index, &mut inserts);
+            self.update_stats.cells_entered += count_cells(&inserts[entered..]);
+            self.update_stats.moved += 1;
+        }
+        self.remove_grouped(&mut removals);
+        for (index, pos, size, footprint) in moves {
+            let record = record_mut(&mut self.slots, index);
+            record.view.pos = pos;
+            record.view.size = size;
+            if record.footprint != footprint {
+                self.carry_positions(index, &footprint);
+            }
+        }
+        self.insert_grouped(&mut inserts);
+        results
+    }
+
+    /// Adds a `(cell, index)` entry for every cell of the footprint, and one with
+    /// `None` for the outside bucket.
+    fn push_cells(&self, footprint: &Footprint, index: u32, out: &mut Vec<(Option<Cell>, u32)>) {
+        if footprint.outside {
+            out.push((None, index));
+        }
+        if let Some((start, end)) = footprint.cells {
+            for row in start.row..=end.row {
+                out.extend((start.col..=end.col).map(|col| (Some(Cell { col, row }), index)));
+            }
+        }
+    }
+
+    /// Like [`Self::push_cells`], but only for the cells of `from` that `to`
+    /// does not cover.
+    fn push_cells_left(
+        &self,
+        from: &Footprint,
+        to: &Footprint,
+        index: u32,
+        out: &mut Vec<(Option<Cell>, u32)>,
+    ) {
+        if from.outside && !to.outside {
+            out.push((None, index));
+        }
+        if let Some((start, end)) = from.cells {
+            for row in start.row..=end.row {
+                out.extend(
+                    (start.col..=end.col)
+                        .map(|col| Cell { col, row })
+                        .filter(|cell| !to.covers(cell))
+                        .map(|cell| (Some(cell), index)),
+                );
+            }
+        }
+    }
+
+    /// Puts the entities into their buckets, looking every bucket up only once.
+    /// The records have to carry their new footprints already.
+    fn insert_grouped(&mut self, inserts: &mut [(Option<Cell>, u32)]) {
+        inserts.sort_by_key(|(cell, _)| cell.map(|cell| (cell.row, cell.col)));
+        for group in inserts.chunk_by(|a, b| a.0 == b.0) {
+            let Some(cell) = group[0].0 else {
+                group
+                    .iter()
+                    .for_each(|&(_, index)| self.push_outside(index));
+                continue;
+            };
+            self.grow_extent(&cell, &cell);
+            let vec = bucket_with_room(&mut self.cells, &mut self.pool, cell, group.len());
+            for &(_, index) in group {
+                *bucket_position(&mut self.slots, index, &cell) = vec.len() as u32;
+                vec.push(index);
+            }
+        }
+    }
+
+    /// Takes the entities out of their buckets, looking every bucket up only once.
+    fn remove_grouped(&mut self, removals: &mut [(Option<Cell>, u32)]) {
+        removals.sort_by_key(|(cell, _)| cell.map(|cell| (cell.row, cell.col)));
+        for group in removals.chunk_by(|a, b| a.0 == b.0) {
+            let Some(cell) = group[0].0 else {
+                group
+                    .iter()
+                    .for_each(|&(_, index)| self.remove_outside(index));
+                continue;
+            };
+            let vec = self
+                .cells
+                .get_mut(&cell)
+                .expect("entities sit in every cell of their footprint");
+            for &(_, index) in group {
+                swap_out(&mut self.slots, vec, &cell, index);
+            }
+            if vec.is_empty() {
+                self.remove_bucket(&cell);
+            }
+        }
+    }
+}
+
+/// Buckets among the entries, leaving out the outside bucket like
+/// [`SpatialHash::update_stats`] does.
+fn count_cells(entries: &[(Option<Cell>, u32)]) -> u64 {
+    entries.iter().filter(|(cell, _)| cell.is_some()).count() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_many_reports_each_item() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(2.0, 2.0);
+
+        // Act
+        let results = grid.create_many([
+            (Vec2::new(10.0, 10.0), size.clone(), 'a'),
+            (Vec2::new(500.0, 10.0), size.clone(), 'x'),
+            (Vec2::new(10.5, 10.0), size.clone(), 'b'),
+        ]);
+
+        // Assert
+        assert_eq!(results.len(), 3);
+        assert!(matches!(results[1], Err(Error::OutOfBounds)));
+        let a = *results[0].as_ref().unwrap();
+        let b = *results[2].as_ref().unwrap();
+        assert_eq!(grid.data(a), Some(&'a'));
+        assert_eq!(grid.data(b), Some(&'b'));
+        let mut found = grid.query_rect(Vec2::new(9.0, 9.0), Vec2::new(11.0, 11.0));
+        found.sort_by_key(|handle| handle.index());
+        assert_eq!(found, vec![a, b]);
+        assert_eq!(grid.collision_pairs().count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn remove_many_skips_stale_and_repeated_ids() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(2.0, 2.0);
+        let a = grid.create(Vec2::new(10.0, 10.0), size.clone(), 1)?;
+        let b = grid.create(Vec2::new(11.0, 10.0), size.clone(), 2)?;
+        let c = grid.create(Vec2::new(50.0, 50.0), size, 3)?;
+        let gone = grid.create(Vec2::new(60.0, 60.0), Vec2::new(1.0, 1.0), 4)?;
+        grid.remove(gone)?;
+
+        // Act
+        let results = grid.remove_many([a, gone, c, a]);
+
+        // Assert
+        assert!(matches!(results[0], Ok(1)));
+        assert!(matches!(results[1], Err(Error::StaleHandle)));
+        assert!(matches!(results[2], Ok(3)));
+        assert!(matches!(results[3], Err(Error::StaleHandle)));
+        assert!(!grid.contains(a) && !grid.contains(c));
+        assert_eq!(
+            grid.query_rect(Vec2::new(0.0, 0.0), Vec2::new(99.0, 99.0)),
+            vec![b]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn update_many_moves_accepted_items_only() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid().with_bounds_policy(crate::BoundsPolicy::Overflow);
+        let size = Vec2::new(2.0, 2.0);
+        let a = grid.create(Vec2::new(10.0, 10.0), size.clone(), ())?;
+        let b = grid.create(Vec2::new(20.0, 20.0), size.clone(), ())?;
+        let c = grid.create(Vec2::new(30.0, 30.0), size.clone(), ())?;
+        grid.remove(c)?;
+
+        // Act
+        let results = grid.update_many([
+            (a, Vec2::new(70.0, 70.0), size.clone()),
+            (c, Vec2::new(40.0, 40.0), size.clone()),
+            (b, Vec2::new(f32::NAN, 20.0), size.clone()),
+            (a, Vec2::new(150.0, 50.0), size.clone()),
+        ]);
+
+        // Assert
+        assert!(results[0].is_ok() && results[3].is_ok());
+        assert!(matches!(results[1], Err(Error::StaleHandle)));
+        assert!(matches!(results[2], Err(Error::OutOfBounds)));
+        assert_eq!(grid.get(a).unwrap().pos(), &Vec2::new(150.0, 50.0));
+        assert_eq!(grid.get(b).unwrap().pos(), &Vec2::new(20.0, 20.0));
+        assert!(
+            grid.query_rect(Vec2::new(60.0, 60.0), Vec2::new(80.0, 80.0))
+                .is_empty()
+        );
+        assert_eq!(grid.outside, vec![a.index()]);
+        assert_eq!(
+            grid.query_rect(Vec2::new(19.0, 19.0), Vec2::new(21.0, 21.0)),
+            vec![b]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn update_many_touches_changed_cells_only() -> anyhow::Result<(), Error> {
+        // Arrange: `a` covers 3x3 cells, `c` shares the middle one
+        let mut grid = create_grid();
+        let a = grid.create(Vec2::new(10.0, 10.0), Vec2::new(6.0, 6.0), ())?;
+        let b = grid.create(Vec2::new(50.0, 50.0), Vec2::new(1.0, 1.0), ())?;
+        let c = grid.create(Vec2::new(10.0, 10.0), Vec2::new(1.0, 1.0), ())?;
+        grid.reset_update_stats();
+
+        // Act: `a` moves one column over, `b` stays in its cell
+        let results = grid.update_many([
+            (a, Vec2::new(14.0, 10.0), Vec2::new(6.0, 6.0)),
+            (b, Vec2::new(50.5, 50.0), Vec2::new(1.0, 1.0)),
+        ]);
+
+        // Assert
+        assert!(results.iter().all(Result::is_ok));
+        let stats = grid.update_stats();
+        assert_eq!(stats.unchanged, 1);
+        assert_eq!(stats.moved, 1);
+        assert_eq!(stats.cells_left, 3);
+        assert_eq!(stats.cells_entered, 3);
+        assert_eq!(grid.get(b).unwrap().pos(), &Vec2::new(50.5, 50.0));
+        assert!(
+            grid.query_rect(Vec2::new(4.0, 4.0), Vec2::new(7.0, 15.0))
+                .is_empty()
+        );
+        grid.remove(c)?;
+        grid.remove(a)?;
+        assert_eq!(
+            grid.query_rect(Vec2::new(0.0, 0.0), Vec2::new(99.0, 99.0)),
+            vec![b]
+        );
+        Ok(())
+    }
+
+    fn create_grid<T>() -> SpatialHash<T> {
+        let cell_size = Vec2::new(4.0, 4.0);
+        let start = Vec2::new(0.0, 0.0);
+        let end = Vec2::new(99.0, 99.0);
+        SpatialHash::new(cell_size, start, end).unwrap()
+    }
+}
@@ -0,0 +1,501 @@
+use crate::error::Error;
+use crate::query::{Seen, with_local_scratch};
+use crate::scalar::Scalar;
+use crate::storage::{CellStorage, HashMapStorage};
+use crate::vec2::*;
+use crate::{Cell, EntityHandle, EntityView, Slot, SpatialHash, allocate_slot};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+thread_local! {
+    // bucket copies of the query running on this thread
+    static CANDIDATES: RefCell<Vec<u32>> = RefCell::default();
+}
+
+/// Number of shards [`ConcurrentSpatialHash::new`] splits the cells into.
+pub const DEFAULT_SHARDS: usize = 64;
+
+/// Bounded grid that several threads can change and query at the same time.
+///
+/// The cells are split into shards by cell key, each behind its own `RwLock`,
+/// so threads moving entities in different parts of the world rarely wait for
+/// each other. Every entity has a lock of its own; creating and removing
+/// entities locks the entity table just long enough to hand out or take back
+/// a slot, the cells are changed after letting go of it.
+///
+/// Queries report every entity at most once and test it at its current
+/// bounds. An entity moving while a query runs may or may not be reported.
+/// They hold a shard only to copy a bucket and the entity table only to look
+/// up a record, so long queries do not hold up `create` or `remove`.
+#[derive(Debug)]
+pub struct ConcurrentSpatialHash<T = (), N = f32> {
+    // never holds entities, only works out which cells a box covers
+    layout: SpatialHash<(), HashMapStorage, N>,
+    shards: Box<[RwLock<Shard>]>,
+    entities: RwLock<Entities<T, N>>,
+}
+
+#[derive(Debug)]
+struct Entities<T, N> {
+    slots: Vec<Slot<SharedRecord<T, N>>>,
+    free: Vec<u32>,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    cells: HashMapStorage,
+    // where an entity sits inside the bucket of a cell, so it can be taken
+    // out without searching
+    positions: HashMap<(Cell, u32), u32>,
+}
+
+// shared so a move can go on after letting go of the entity table
+type SharedRecord<T, N> = Arc<Mutex<Record<T, N>>>;
+
+#[derive(Debug)]
+struct Record<T, N> {
+    view: EntityView<N>,
+    // taken out by `remove`, a record without data is gone
+    data: Option<T>,
+    // first and last cell of the footprint
+    cells: (Cell, Cell),
+}
+
+impl<T, N: Scalar> ConcurrentSpatialHash<T, N> {
+    pub fn new(cell_size: Vec2<N>, start: Vec2<N>, end: Vec2<N>) -> anyhow::Result<Self, Error> {
+        Self::with_shards(cell_size, start, end, DEFAULT_SHARDS)
+    }
+
+    /// Same grid as [`ConcurrentSpatialHash::new`] with the cells split into
+    /// `shards` shards (at least one).
+    pub fn with_shards(
+        cell_size: Vec2<N>,
+        start: Vec2<N>,
+        end: Vec2<N>,
+        shards: usize,
+    ) -> anyhow::Result<Self, Error> {
+        Ok(Self {
+            layout: SpatialHash::new(cell_size, start, end)?,
+            shards: (0..shards.max(1)).map(|_| RwLock::default()).collect(),
+            entities: RwLock::new(Entities {
+                slots: Vec::new(),
+                free: Vec::new(),
+            }),
+        })
+    }
+
+    pub fn create(
+        &self,
+        pos: Vec2<N>,
+        size: Vec2<N>,
+        data: T,
+    ) -> anyhow::Result<EntityHandle, Error> {
+        let cells = self.cells_of(&pos, &size)?;
+        let handle = {
+            let mut entities = write(&self.entities);
+            let Entities { slots, free } = &mut *entities;
+            let handle = allocate_slot(slots, free);
+            slots[handle.index as usize].entity = Some(Arc::new(Mutex::new(Record {
+                view: EntityView { handle, pos, size },
+                data: Some(data),
+                cells,
+            })));
+            handle
+        };
+        // nobody else knows the handle yet, so nobody moves the entity meanwhile
+        self.enter(cells, None, handle.index);
+        Ok(handle)
+    }
+
+    pub fn remove(&self, id: EntityHandle) -> anyhow::Result<T, Error> {
+        let (record, reusable) = {
+            let mut entities = write(&self.entities);
+            match entities.slots.get_mut(id.index as usize) {
+                Some(slot) if slot.generation == id.generation && slot.entity.is_some() => {
+                    slot.vacate()
+                }
+                _ => return Err(Error::StaleHandle),
+            }
+        };
+        // waits for a move of the entity that is already under way
+        let data = {
+            let mut record = lock(&record);
+            self.leave(record.cells, None, id.index);
+            record.data.take().expect("only removed once")
+        };
+        // the slot is only handed out again once the cells no longer list it
+        if reusable {
+            write(&self.entities).free.push(id.index);
+        }
+        Ok(data)
+    }
+
+    /// Moves and resizes the entity. Other entities can be moved by other
+    /// threads at the same time.
+    pub fn update(
+        &self,
+        id: EntityHandle,
+        pos: Vec2<N>,
+        size: Vec2<N>,
+    ) -> anyhow::Result<(), Error> {
+        self.update_with(id, |_| (pos, size))
+    }
+
+    /// Moves the entity, keeping the size it has when the move happens.
+    pub fn set_position(&self, id: EntityHandle, pos: Vec2<N>) -> anyhow::Result<(), Error> {
+        self.update_with(id, |view| (pos, view.size.clone()))
+    }
+
+    /// A copy of the entity as it is right now.
+    pub fn get(&self, id: EntityHandle) -> Option<EntityView<N>> {
+        let record = self.record(id).ok()?;
+        let record = lock(&record);
+        record.data.as_ref().map(|_| record.view.clone())
+    }
+
+    /// Returns true if `id` still refers to a live entity.
+    pub fn contains(&self, id: EntityHandle) -> bool {
+        self.record(id).is_ok()
+    }
+
+    /// Returns every entity whose bounding box overlaps the rectangle from
+    /// `min` to `max`, see [`SpatialHash::query_rect`].
+    pub fn query_rect(&self, min: Vec2<N>, max: Vec2<N>) -> Vec<EntityHandle> {
+        let cells = self.layout.clamped_cell_range(&min, &max);
+        self.query(cells, |view| view.overlaps(&min, &max))
+    }
+
+    /// Returns every entity whose bounding box lies within `radius` of `center`.
+    pub fn query_radius(&self, center: Vec2<N>, radius: N::Float) -> Vec<EntityHandle> {
+        let cells = self.layout.radius_cell_range(&center, radius);
+        self.query(cells, |view| view.distance_to(&center) <= radius)
+    }
+
+    /// Moves the entity to the bounds `bounds` picks from its current view,
+    /// all while holding the lock of the entity.
+    fn update_with(
+        &self,
+        id: EntityHandle,
+        bounds: impl FnOnce(&EntityView<N>) -> (Vec2<N>, Vec2<N>),
+    ) -> anyhow::Result<(), Error> {
+        let record = self.record(id)?;
+        let mut record = lock(&record);
+        if record.data.is_none() {
+            return Err(Error::StaleHandle);
+        }
+        let (pos, size) = bounds(&record.view);
+        let cells = self.cells_of(&pos, &size)?;
+        let old = record.cells;
+        if old != cells {
+            self.enter(cells, Some(old), id.index);
+            self.leave(old, Some(cells), id.index);
+        }
+        record.view.pos = pos;
+        record.view.size = size;
+        record.cells = cells;
+        Ok(())
+    }
+
+    /// The record behind `id`, without keeping the entity table locked.
+    fn record(&self, id: EntityHandle) -> anyhow::Result<SharedRecord<T, N>, Error> {
+        let entities = read(&self.entities);
+        match entities.slots.get(id.index as usize) {
+            Some(Slot {
+                generation,
+                entity: Some(record),
+            }) if *generation == id.generation => Ok(Arc::clone(record)),
+            _ => Err(Error::StaleHandle),
+        }
+    }
+
+    /// Every entity in the `cells` range that passes `hit`. Neither the shards
+    /// nor the entity table stay locked while an entity is tested.
+    fn query(
+        &self,
+        cells: Option<(Cell, Cell)>,
+        hit: impl Fn(&EntityView<N>) -> bool,
+    ) -> Vec<EntityHandle> {
+        let Some((start, end)) = cells else {
+            return Vec::new();
+        };
+        let mut found = Vec::new();
+        let mut candidates = CANDIDATES.take();
+        with_local_scratch(|seen| {
+            seen.begin(0);
+            for col in start.col..=end.col {
+                for row in start.row..=end.row {
+                    let cell = Cell { col, row };
+                    // copy the bucket out, so no shard stays locked while checking entities
+                    candidates.clear();
+                    if let Some(vec) = read(self.shard(&cell)).cells.get(&cell) {
+                        candidates.extend_from_slice(vec);
+                    }
+                    for &index in &candidates {
+                        seen.cover(index as usize + 1);
+                        if !seen.first_visit(index) {
+                            continue;
+                        }
+                        let Some(record) = self.record_at(index) else {
+                            continue;
+                        };
+                        let record = lock(&record);
+                        if record.data.is_some() && hit(&record.view) {
+                            found.push(record.view.handle);
+                        }
+                    }
+                }
+            }
+        });
+        CANDIDATES.set(candidates);
+        found
+    }
+
+    /// The record in slot `index`, whichever entity it belongs to by now.
+    fn record_at(&self, index: u32) -> Option<SharedRecord<T, N>> {
+        read(&self.entities).slots[index as usize].entity.clone()
+    }
+
+    fn cells_of(&self, pos: &Vec2<N>, size: &Vec2<N>) -> anyhow::Result<(Cell, Cell), Error> {
+        let footprint = self.layout.footprint(pos, size)?;
+        Ok(footprint
+            .cells
+            .expect("rejecting grids keep everything in cells"))
+    }
+
+    fn shard(&self, cell: &Cell) -> &RwLock<Shard> {
+        let key = ((cell.row as u32 as u64) << 32) | cell.col as u32 as u64;
+        let hash = key.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+        &self.shards[hash as usize % self.shards.len()]
+    }
+
+    /// Pushes the entity into the cells from `start` to `end` that `skip` does
+    /// not cover, locking one shard at a time.
+    fn enter(&self, (start, end): (Cell, Cell), skip: Option<(Cell, Cell)>, index: u32) {
+        for cell in cells_between(start, end, skip) {
+            let mut shard = write(self.shard(&cell));
+            let Shard { cells, positions } = &mut *shard;
+            let vec = cells.get_or_create(cell);
+            positions.insert((cell, index), vec.len() as u32);
+            vec.push(index);
+        }
+    }
+
+    /// Takes the entity out of the cells from `start` to `end` that `skip`
+    /// does not cover, locking one shard at a time. The last entry of each
+    /// bucket takes its place.
+    fn leave(&self, (start, end): (Cell, Cell), skip: Option<(Cell, Cell)>, index: u32) {
+        for cell in cells_between(start, end, skip) {
+            let mut shard = write(self.shard(&cell));
+            let Shard { cells, positions } = &mut *shard;
+            let at = positions
+                .remove(&(cell, index))
+                .expect("entities sit in every cell of their footprint");
+            let vec = cells
+                .get_mut(&cell)
+                .expect("entities sit in every cell of their footprint");
+            vec.swap_remove(at as usize);
+            if let Some(&moved) = vec.get(at as usize) {
+                positions.insert((cell, moved), at);
+            } else if vec.is_empty() {
+                cells.remove(&cell);
+            }
+        }
+    }
+}
+
+fn cells_between(start: Cell, end: Cell, skip: Option<(Cell, Cell)>) -> impl Iterator<Item = Cell> {
+    let covered = move |cell: &Cell| {
+        skip.is_some_and(|(first, last)| {
+            (first.col..=last.col).contains(&cell.col) && (first.row..=last.row).contains(&cell.row)
+        })
+    };
+    (start.row..=end.row)
+        .flat_map(move |row| (start.col..=end.col).map(move |col| Cell { col, row }))
+        .filter(move |cell| !covered(cell))
+}
+
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read()
+        .expect("a thread panicked while holding the lock")
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write()
+        .expect("a thread panicked while holding the lock")
+}
+
+fn lock<T>(lock: &Mutex<T>) -> MutexGuard<'_, T> {
+    lock.lock()
+        .expect("a thread panicked while holding the lock")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn create_update_remove_from_shared_reference() -> anyhow::Result<(), Error> {
+        // Arrange
+        let grid = create_grid();
+        let size = Vec2::new(2.0, 2.0);
+        let a = grid.create(Vec2::new(10.0, 10.0), size.clone(), 'a')?;
+        let b = grid.create(Vec2::new(50.0, 50.0), size.clone(), 'b')?;
+
+        // Act
+        grid.set_position(a, Vec2::new(48.0, 50.0))?;
+        let removed = grid.remove(b)?;
+
+        // Assert
+        assert_eq!(removed, 'b');
+        assert!(!grid.contains(b));
+        assert!(matches!(grid.remove(b), Err(Error::StaleHandle)));
+        assert_eq!(
+            grid.query_rect(Vec2::new(45.0, 45.0), Vec2::new(55.0, 55.0)),
+            vec![a]
+        );
+        assert!(grid.query_radius(Vec2::new(10.0, 10.0), 3.0).is_empty());
+        assert!(matches!(
+            grid.create(Vec2::new(500.0, 10.0), size, 'x'),
+            Err(Error::OutOfBounds)
+        ));
+        assert_consistent(&grid);
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_moves_never_lose_or_duplicate_ids() -> anyhow::Result<(), Error> {
+        // Arrange
+        let grid = ConcurrentSpatialHash::with_shards(
+            Vec2::new(4.0, 4.0),
+            Vec2::new(0.0, 0.0),
+            Vec2::new(99.0, 99.0),
+            8,
+        )?;
+        let size = Vec2::new(3.0, 3.0);
+        let handles = (0..400)
+            .map(|i| {
+                grid.create(
+                    Vec2::new((i % 20) as f32 * 4.5 + 5.0, (i / 20) as f32 * 4.5 + 5.0),
+                    size.clone(),
+                    i,
+                )
+            })
+            .collect::<anyhow::Result<Vec<_>, Error>>()?;
+
+        // Act: four threads move their own entities around while creating and
+        // removing a few more, two threads keep querying
+        let duplicates = std::thread::scope(|scope| {
+            for (worker, own) in handles.chunks(100).enumerate() {
+                let (grid, size) = (&grid, &size);
+                scope.spawn(move || {
+                    let mut seed = worker as u32 * 7919 + 1;
+                    for step in 0..300 {
+                        for &handle in own {
+                            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                            let x = (seed >> 8) % 90 + 5;
+                            let y = (seed >> 16) % 90 + 5;
+                            grid.set_position(handle, Vec2::new(x as f32, y as f32))
+                                .unwrap();
+                        }
+                        let temp = grid
+                            .create(Vec2::new(50.0, 50.0), size.clone(), step)
+                            .unwrap();
+                        grid.remove(temp).unwrap();
+                    }
+                });
+            }
+            let readers: Vec<_> = (0..2)
+                .map(|_| {
+                    let grid = &grid;
+                    scope.spawn(move || {
+                        let mut duplicates = 0;
+                        for _ in 0..300 {
+                            let found = grid.query_rect(Vec2::new(0.0, 0.0), Vec2::new(99.0, 99.0));
+                            let unique: HashSet<_> = found.iter().collect();
+                            duplicates += found.len() - unique.len();
+                        }
+                        duplicates
+                    })
+                })
+                .collect();
+            readers
+                .into_iter()
+                .map(|reader| reader.join().unwrap())
+                .sum::<usize>()
+        });
+
+        // Assert
+        assert_eq!(duplicates, 0, "no query reports an id twice");
+        assert_consistent(&grid);
+        let mut found = grid.query_rect(Vec2::new(0.0, 0.0), Vec2::new(99.0, 99.0));
+        found.sort_by_key(|handle| handle.index());
+        assert_eq!(found, handles);
+        Ok(())
+    }
+
+    #[test]
+    fn set_position_keeps_a_concurrent_resize() -> anyhow::Result<(), Error> {
+        // Arrange
+        let grid = create_grid::<()>();
+        let small = Vec2::new(1.0, 1.0);
+        let big = Vec2::new(6.0, 6.0);
+        let a = grid.create(Vec2::new(50.0, 50.0), small.clone(), ())?;
+
+        for _ in 0..200 {
+            grid.update(a, Vec2::new(50.0, 50.0), small.clone())?;
+
+            // Act
+            std::thread::scope(|scope| {
+                scope.spawn(|| grid.set_position(a, Vec2::new(20.0, 20.0)).unwrap());
+                scope.spawn(|| grid.update(a, Vec2::new(50.0, 50.0), big.clone()).unwrap());
+            });
+
+            // Assert: whichever ran last, the resize is never undone
+            assert_eq!(grid.get(a).unwrap().size(), &big);
+        }
+        assert_consistent(&grid);
+        Ok(())
+    }
+
+    /// Every live entity sits exactly once in every cell of its footprint and
+    /// nowhere else.
+    fn assert_consistent<T>(grid: &ConcurrentSpatialHash<T>) {
+        let mut stored: HashMap<(Cell, u32), usize> = HashMap::new();
+        for shard in grid.shards.iter() {
+            let shard = read(shard);
+            for (cell, vec) in shard.cells.iter() {
+                assert!(!vec.is_empty());
+                for (at, &index) in vec.iter().enumerate() {
+                    *stored.entry((cell, index)).or_default() += 1;
+                    assert_eq!(shard.positions.get(&(cell, index)), Some(&(at as u32)));
+                }
+            }
+            assert_eq!(
+                shard.positions.len(),
+                shard.cells.values().map(|vec| vec.len()).sum::<usize>()
+            );
+        }
+        let entities = read(&grid.entities);
+        let mut expected = 0;
+        for (index, slot) in entities.slots.iter().enumerate() {
+            let Some(record) = &slot.entity else {
+                continue;
+            };
+            let (start, end) = lock(record).cells;
+            for cell in cells_between(start, end, None) {
+                assert_eq!(stored.get(&(cell, index as u32)), Some(&1));
+                expected += 1;
+            }
+        }
+        assert_eq!(stored.len(), expected);
+    }
+
+    fn create_grid<T>() -> ConcurrentSpatialHash<T> {
+        let cell_size = Vec2::new(4.0, 4.0);
+        let start = Vec2::new(0.0, 0.0);
+        let end = Vec2::new(99.0, 99.0);
+        ConcurrentSpatialHash::new(cell_size, start, end).unwrap()
+    }
+}
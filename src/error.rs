@@ -4,6 +4,12 @@ use std::fmt;
 pub enum Error {
     NumCellsEqualZero,
     OutOfBounds,
+    StaleHandle,
+    InvalidCellSize,
+    StorageNeedsBounds,
+    FootprintTooLarge,
+    TooManyCells,
+    NegativeSize,
     // add other variants
 }
 
@@ -12,6 +18,12 @@ impl fmt::Display for Error {
         match self {
             Error::NumCellsEqualZero => write!(f, "number of cells must be greater than zero"),
             Error::OutOfBounds => write!(f, "Placed Entity out of Bounds!"),
+            Error::StaleHandle => write!(f, "entity handle refers to a removed entity"),
+            Error::InvalidCellSize => write!(f, "cell size must be greater than zero"),
+            Error::StorageNeedsBounds => write!(f, "cell storage only supports bounded grids"),
+            Error::FootprintTooLarge => write!(f, "entity covers too many cells"),
+            Error::TooManyCells => write!(f, "grid has too many cells for dense storage"),
+            Error::NegativeSize => write!(f, "entity size must not be negative"),
         }
     }
 }
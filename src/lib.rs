@@ -1,463 +1,2544 @@
 #![allow(dead_code)]
 #![allow(unused)]
+mod bulk;
+pub mod concurrent;
 pub mod error;
+pub mod pairs;
+#[cfg(feature = "parallel")]
+mod parallel;
+pub mod query;
+pub mod ray;
+mod rebuild;
+pub mod scalar;
+pub mod snapshot;
+pub mod storage;
 pub mod vec2;
 use crate::error::Error;
+use crate::query::{QueryStamps, Seen};
+use crate::rebuild::SortedCells;
+use crate::scalar::{Float, Scalar};
+use crate::storage::{Bucket, CellStorage, DenseStorage, HashMapStorage};
 use crate::vec2::*;
-use std::collections::{HashMap, HashSet};
+use smallvec::SmallVec;
+use std::collections::HashSet;
+use std::sync::atomic::AtomicU32;
 
-#[derive(Debug, Eq, Hash, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, Ord, PartialOrd)]
 pub struct Cell {
     col: i32,
     row: i32,
 }
 impl Cell {
-    fn new(pos: &Vec2, spatial_hash: &SpatialHash) -> anyhow::Result<Self, Error> {
-        // TODO turn this into a "CheckedBoundsVec"
-        if *pos < spatial_hash.start {
+    pub fn col(&self) -> i32 {
+        self.col
+    }
+
+    pub fn row(&self) -> i32 {
+        self.row
+    }
+
+    fn new<T, S: CellStorage, N: Scalar>(
+        pos: &Vec2<N>,
+        spatial_hash: &SpatialHash<T, S, N>,
+    ) -> anyhow::Result<Self, Error> {
+        if !spatial_hash.in_bounds(pos) {
             return Err(Error::OutOfBounds);
         }
-        let rel_start = (pos - &spatial_hash.start) / (&spatial_hash.end - &spatial_hash.start);
-        Ok(Self {
-            col: (rel_start.x * spatial_hash.num_cells.cols as f32).floor() as i32,
-            row: (rel_start.y * spatial_hash.num_cells.rows as f32).floor() as i32,
+        Ok(Self::unchecked(pos, spatial_hash))
+    }
+
+    /// Cell the position falls into, even if that is outside of the grid.
+    /// Indices beyond `i32` saturate, which only suits queries.
+    fn unchecked<T, S, N: Scalar>(pos: &Vec2<N>, spatial_hash: &SpatialHash<T, S, N>) -> Self {
+        let (start, size) = (&spatial_hash.start, &spatial_hash.cell_size);
+        let saturate = |index: i64| index.clamp(i32::MIN.into(), i32::MAX.into()) as i32;
+        Self {
+            col: saturate(N::cell_index(pos.x, start.x, size.x)),
+            row: saturate(N::cell_index(pos.y, start.y, size.y)),
+        }
+    }
+
+    /// Cell the position falls into, `None` if its index does not fit into `i32`.
+    fn fitting<T, S, N: Scalar>(
+        pos: &Vec2<N>,
+        spatial_hash: &SpatialHash<T, S, N>,
+    ) -> Option<Self> {
+        let (start, size) = (&spatial_hash.start, &spatial_hash.cell_size);
+        Some(Self {
+            col: N::cell_index(pos.x, start.x, size.x).try_into().ok()?,
+            row: N::cell_index(pos.y, start.y, size.y).try_into().ok()?,
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Dimensions {
     cols: u32,
     rows: u32,
 }
 
+impl Dimensions {
+    pub fn cols(&self) -> u32 {
+        self.cols
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.rows
+    }
+}
+
+/// Most cells a single entity may cover. Larger entities are refused with
+/// [`Error::FootprintTooLarge`] instead of being put into every one of them.
+pub const MAX_FOOTPRINT_CELLS: usize = 1 << 20;
+
+/// What happens to entities that reach beyond the bounds of the grid.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BoundsPolicy {
+    /// Refuse them with [`Error::OutOfBounds`].
+    #[default]
+    Reject,
+    /// Store them in the edge cells closest to them.
+    Clamp,
+    /// Keep them in a dedicated bucket outside of the grid, which every query
+    /// checks as well.
+    Overflow,
+}
+
+/// Identifies an entity inside a [`SpatialHash`].
+///
+/// The `index` names a slot that gets reused once the entity is removed, the
+/// `generation` tells the different occupants of that slot apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EntityHandle {
+    index: u32,
+    generation: u32,
+}
+impl EntityHandle {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+/// Place of one entity, shared by the entities that occupy it one after another.
+#[derive(Debug, Clone)]
+struct Slot<E> {
+    generation: u32,
+    entity: Option<E>,
+}
+
+impl<E> Slot<E> {
+    /// Takes the entity out and moves on to the next generation, so handles to
+    /// it go stale. The flag tells whether the slot can be handed out again.
+    fn vacate(&mut self) -> (E, bool) {
+        let entity = self.entity.take().expect("handle checked by caller");
+        // a slot whose generation would wrap is retired instead of reused, so an
+        // old handle can never become valid again
+        if self.generation == u32::MAX {
+            return (entity, false);
+        }
+        self.generation += 1;
+        (entity, true)
+    }
+}
+
+/// Hands out a slot from the free-list, or a new one at the end, and returns
+/// the handle its next entity gets.
+fn allocate_slot<E>(slots: &mut Vec<Slot<E>>, free: &mut Vec<u32>) -> EntityHandle {
+    if let Some(index) = free.pop() {
+        return EntityHandle {
+            index,
+            generation: slots[index as usize].generation,
+        };
+    }
+    let index = slots.len() as u32;
+    slots.push(Slot {
+        generation: 0,
+        entity: None,
+    });
+    EntityHandle {
+        index,
+        generation: 0,
+    }
+}
+
 #[derive(Debug)]
-pub struct Entity {
-    pos: Vec2,
-    start: Cell,
-    end: Cell,
-    size: Vec2,
-    id: u32,
+struct EntityRecord<T, N> {
+    view: EntityView<N>,
+    data: T,
+    footprint: Footprint,
+    // where the entity sits inside the bucket of each footprint cell, row by row,
+    // so it can be taken out without searching
+    positions: SmallVec<[u32; 4]>,
+    outside_at: u32,
+    // epoch of the last query that came across the entity
+    visited: AtomicU32,
 }
-impl Entity {
-    fn new(pos: Vec2, start: Cell, end: Cell, size: Vec2, id: u32) -> Self {
+
+impl<T: Clone, N: Clone> Clone for EntityRecord<T, N> {
+    fn clone(&self) -> Self {
         Self {
-            pos,
-            start,
-            end,
-            size,
-            id,
+            view: self.view.clone(),
+            data: self.data.clone(),
+            footprint: self.footprint,
+            positions: self.positions.clone(),
+            outside_at: self.outside_at,
+            // the copy gets fresh query stamps, which start over at epoch zero
+            visited: AtomicU32::new(0),
         }
     }
 }
 
+/// How [`SpatialHash::update`] got its work done, counted since the grid was
+/// created or [`SpatialHash::reset_update_stats`] was called.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UpdateStats {
+    /// Updates that stayed within the same cells and touched no bucket.
+    pub unchanged: u64,
+    /// Updates that moved the entity into other cells.
+    pub moved: u64,
+    /// Buckets the moved entities were taken out of.
+    pub cells_left: u64,
+    /// Buckets the moved entities were put into.
+    pub cells_entered: u64,
+}
+
+/// Where an entity is stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Footprint {
+    /// First and last cell covered, `None` if the entity only lives outside the grid.
+    cells: Option<(Cell, Cell)>,
+    /// The entity reaches beyond the bounds and sits in the outside bucket.
+    outside: bool,
+}
+
+impl Footprint {
+    /// Number of cells covered.
+    fn len(&self) -> usize {
+        self.cells
+            .map_or(0, |(start, end)| cell_count(&start, &end) as usize)
+    }
+
+    /// Position of a covered cell when going through them row by row.
+    fn offset(&self, cell: &Cell) -> usize {
+        let (start, end) = self.cells.expect("cell covered by the footprint");
+        let width = (end.col as i64 - start.col as i64 + 1) as usize;
+        (cell.row as i64 - start.row as i64) as usize * width
+            + (cell.col as i64 - start.col as i64) as usize
+    }
+
+    fn covers(&self, cell: &Cell) -> bool {
+        self.cells.is_some_and(|(start, end)| {
+            (start.col..=end.col).contains(&cell.col) && (start.row..=end.row).contains(&cell.row)
+        })
+    }
+}
+
+/// Read-only view of an entity stored in a [`SpatialHash`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityView<N = f32> {
+    handle: EntityHandle,
+    pos: Vec2<N>,
+    size: Vec2<N>,
+}
+impl<N: Scalar> EntityView<N> {
+    pub fn handle(&self) -> EntityHandle {
+        self.handle
+    }
+    pub fn pos(&self) -> &Vec2<N> {
+        &self.pos
+    }
+    pub fn size(&self) -> &Vec2<N> {
+        &self.size
+    }
+    /// Lower corner of the bounding box.
+    pub fn min(&self) -> Vec2<N> {
+        self.bounds().0
+    }
+    /// Upper corner of the bounding box. Integer boxes end on the last tile
+    /// they cover, see [`Scalar`].
+    pub fn max(&self) -> Vec2<N> {
+        self.bounds().1
+    }
+
+    fn bounds(&self) -> (Vec2<N>, Vec2<N>) {
+        let fits = "stored boxes were checked to fit";
+        let (min_x, max_x) = N::box_bounds(self.pos.x, self.size.x).expect(fits);
+        let (min_y, max_y) = N::box_bounds(self.pos.y, self.size.y).expect(fits);
+        (Vec2::new(min_x, min_y), Vec2::new(max_x, max_y))
+    }
+
+    /// Distance from `point` to the closest point of the bounding box, zero if
+    /// the point lies inside.
+    pub fn distance_to(&self, point: &Vec2<N>) -> N::Float {
+        let (min, max) = (self.min().to_float(), self.max().to_float());
+        let point = point.to_float();
+        let zero = N::Float::ZERO;
+        let dx = (min.x - point.x).max(point.x - max.x).max(zero);
+        let dy = (min.y - point.y).max(point.y - max.y).max(zero);
+        (dx * dx + dy * dy).sqrt()
+    }
+
+    fn overlaps(&self, min: &Vec2<N>, max: &Vec2<N>) -> bool {
+        let (own_min, own_max) = (self.min(), self.max());
+        own_min.x <= max.x && own_max.x >= min.x && own_min.y <= max.y && own_max.y >= min.y
+    }
+}
+
+/// Grid of entities, each carrying a user payload of type `T`.
+///
+/// `SpatialHash` without a type argument stores no payload, queries then only
+/// hand out [`EntityHandle`]s. The cells live in `S`, see [`CellStorage`].
+/// Coordinates and sizes are of type `N`, see [`Scalar`].
 #[derive(Debug)]
-pub struct SpatialHash {
-    cells: HashMap<Cell, Vec<u32>>, // Cellindex + uuids
-    start: Vec2,
-    end: Vec2,
-    num_cells: Dimensions,
-    id: u32,
+pub struct SpatialHash<T = (), S = HashMapStorage, N = f32> {
+    cells: S, // Cellindex + slot indices
+    // layout of the last `rebuild_from`, read instead of `cells` until the next change
+    frozen: Option<SortedCells>,
+    start: Vec2<N>,
+    end: Vec2<N>,
+    cell_size: Vec2<N>,
+    num_cells: Option<Dimensions>, // None for unbounded grids
+    // first and last cell queries have to look at: the whole grid if bounded,
+    // otherwise every cell used so far
+    extent: Option<(Cell, Cell)>,
+    bounds_policy: BoundsPolicy,
+    outside: Vec<u32>, // slot indices of entities beyond the bounds
+    slots: Vec<Slot<EntityRecord<T, N>>>,
+    free: Vec<u32>,
+    update_stats: UpdateStats,
+    // swapped with an entity's positions when it moves, so moving does not allocate
+    spare_positions: SmallVec<[u32; 4]>,
+    // emptied buckets that own heap memory, taken over by buckets that outgrow
+    // their inline storage
+    pool: Vec<Bucket>,
+    // epoch of the query stamping the entity records, see `Visited`
+    query_stamps: QueryStamps,
 }
 
-impl SpatialHash {
-    pub fn new(cell_size: Vec2, start: Vec2, end: Vec2) -> anyhow::Result<Self, Error> {
-        let cells = HashMap::new();
-        // if start 0 and end 99 then this corrects it to 100 entries
-        let end = end.add(1.0);
-        let num_cells_rel = ((&end - &start) / &cell_size).ceil();
-        let padded_end = &start + &num_cells_rel * &cell_size;
+impl<T: Clone, S: Clone, N: Clone> Clone for SpatialHash<T, S, N> {
+    fn clone(&self) -> Self {
+        Self {
+            cells: self.cells.clone(),
+            frozen: self.frozen.clone(),
+            start: self.start.clone(),
+            end: self.end.clone(),
+            cell_size: self.cell_size.clone(),
+            num_cells: self.num_cells.clone(),
+            extent: self.extent,
+            bounds_policy: self.bounds_policy,
+            outside: self.outside.clone(),
+            slots: self.slots.clone(),
+            free: self.free.clone(),
+            update_stats: self.update_stats,
+            // scratch memory, the copy grows its own
+            spare_positions: SmallVec::new(),
+            pool: Vec::new(),
+            query_stamps: self.query_stamps.clone(),
+        }
+    }
 
-        if num_cells_rel.x <= 0.0 || num_cells_rel.y <= 0.0 {
-            return Err(Error::NumCellsEqualZero);
+    /// Copies `source` into this grid, reusing the memory it already has.
+    fn clone_from(&mut self, source: &Self) {
+        self.cells.clone_from(&source.cells);
+        self.frozen.clone_from(&source.frozen);
+        self.start.clone_from(&source.start);
+        self.end.clone_from(&source.end);
+        self.cell_size.clone_from(&source.cell_size);
+        self.num_cells.clone_from(&source.num_cells);
+        self.extent = source.extent;
+        self.bounds_policy = source.bounds_policy;
+        self.outside.clone_from(&source.outside);
+        self.slots.clone_from(&source.slots);
+        self.free.clone_from(&source.free);
+        self.update_stats = source.update_stats;
+    }
+}
+
+impl<T, N: Scalar> SpatialHash<T, HashMapStorage, N> {
+    pub fn new(cell_size: Vec2<N>, start: Vec2<N>, end: Vec2<N>) -> anyhow::Result<Self, Error> {
+        Self::with_storage(cell_size, start, end)
+    }
+
+    /// A grid without bounds: any finite position maps to the cell
+    /// `floor(pos / cell_size)`, so the world can grow freely. Cells are
+    /// numbered with `i32`; positions past the last cell are refused with
+    /// [`Error::OutOfBounds`].
+    pub fn unbounded(cell_size: Vec2<N>) -> anyhow::Result<Self, Error> {
+        Self::unbounded_with_storage(cell_size)
+    }
+}
+
+impl<T, N: Scalar> SpatialHash<T, DenseStorage, N> {
+    /// Same grid as [`SpatialHash::new`], but the cells live in one flat array
+    /// instead of a `HashMap`, see [`DenseStorage`]. Grids of more than
+    /// [`MAX_DENSE_CELLS`](storage::MAX_DENSE_CELLS) cells are refused.
+    pub fn dense(cell_size: Vec2<N>, start: Vec2<N>, end: Vec2<N>) -> anyhow::Result<Self, Error> {
+        Self::with_storage(cell_size, start, end)
+    }
+}
+
+impl<T, S: CellStorage, N: Scalar> SpatialHash<T, S, N> {
+    /// Bounded grid like [`SpatialHash::new`] keeping its cells in `S`.
+    pub fn with_storage(
+        cell_size: Vec2<N>,
+        start: Vec2<N>,
+        end: Vec2<N>,
+    ) -> anyhow::Result<Self, Error> {
+        // also refuses NaN
+        if !(cell_size.x > N::ZERO && cell_size.y > N::ZERO) {
+            return Err(Error::InvalidCellSize);
         }
+        // if start 0 and end 99 then this corrects it to 100 entries
+        let len = |start: N, end: N| end.checked_add(N::ONE)?.checked_sub(start);
+        let (Some(width), Some(height)) = (len(start.x, end.x), len(start.y, end.y)) else {
+            return Err(Error::OutOfBounds);
+        };
         let num_cells = Dimensions {
-            cols: num_cells_rel.x as u32,
-            rows: num_cells_rel.y as u32,
+            cols: N::cells_to_cover(width, cell_size.x),
+            rows: N::cells_to_cover(height, cell_size.y),
+        };
+        if num_cells.cols == 0 || num_cells.rows == 0 {
+            return Err(Error::NumCellsEqualZero);
+        }
+        // cells are numbered with `i32`, the last one has to fit
+        if num_cells.cols > i32::MAX as u32 || num_cells.rows > i32::MAX as u32 {
+            return Err(Error::OutOfBounds);
+        }
+        let padded = |start: N, cells: u32, size: N| {
+            N::from_index(cells.into())
+                .checked_mul(size)?
+                .checked_add(start)
         };
+        let (Some(end_x), Some(end_y)) = (
+            padded(start.x, num_cells.cols, cell_size.x),
+            padded(start.y, num_cells.rows, cell_size.y),
+        ) else {
+            return Err(Error::OutOfBounds);
+        };
+        let padded_end = Vec2::new(end_x, end_y);
         // dbg! {&start, &end, &num_cells_rel, &cell_size, &num_cells};
-        let id = 0u32;
+        let cells = S::for_grid(Some(&num_cells))?;
+        let last = Cell {
+            col: num_cells.cols as i32 - 1,
+            row: num_cells.rows as i32 - 1,
+        };
         Ok(Self {
             cells,
+            frozen: None,
             start,
             end: padded_end,
-            num_cells,
-            id,
+            cell_size,
+            num_cells: Some(num_cells),
+            extent: Some((Cell { col: 0, row: 0 }, last)),
+            bounds_policy: BoundsPolicy::default(),
+            outside: Vec::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            update_stats: UpdateStats::default(),
+            spare_positions: SmallVec::new(),
+            pool: Vec::new(),
+            query_stamps: QueryStamps::default(),
+        })
+    }
+
+    /// Unbounded grid like [`SpatialHash::unbounded`] keeping its cells in `S`.
+    pub fn unbounded_with_storage(cell_size: Vec2<N>) -> anyhow::Result<Self, Error> {
+        // also refuses NaN
+        if !(cell_size.x > N::ZERO && cell_size.y > N::ZERO) {
+            return Err(Error::InvalidCellSize);
+        }
+        Ok(Self {
+            cells: S::for_grid(None)?,
+            frozen: None,
+            start: Vec2::new(N::ZERO, N::ZERO),
+            // never read, unbounded grids have no end
+            end: Vec2::new(N::ZERO, N::ZERO),
+            cell_size,
+            num_cells: None,
+            extent: None,
+            bounds_policy: BoundsPolicy::default(),
+            outside: Vec::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            update_stats: UpdateStats::default(),
+            spare_positions: SmallVec::new(),
+            pool: Vec::new(),
+            query_stamps: QueryStamps::default(),
         })
     }
 
-    pub fn create(self: &mut Self, pos: Vec2, size: Vec2) -> anyhow::Result<Entity, Error> {
-        let (start_pos, end_pos) = Self::get_start_and_end(&pos, &size);
-        let start_idx = Cell::new(&start_pos, self)?;
-        let end_idx = Cell::new(&end_pos, self)?;
-        let entity = Entity::new(pos, start_idx, end_idx, size, self.id);
-        self.insert(&entity);
-        self.id += 1;
-        Ok(entity)
+    /// Sets how entities beyond the bounds are handled, see [`BoundsPolicy`].
+    pub fn with_bounds_policy(mut self, bounds_policy: BoundsPolicy) -> Self {
+        self.bounds_policy = bounds_policy;
+        self
+    }
+
+    /// Adds an entity covering `pos..=pos + size`. Negative sizes are
+    /// refused with [`Error::NegativeSize`]; a zero size covers one point.
+    pub fn create(
+        &mut self,
+        pos: Vec2<N>,
+        size: Vec2<N>,
+        data: T,
+    ) -> anyhow::Result<EntityHandle, Error> {
+        let footprint = self.footprint(&pos, &size)?;
+        let handle = self.allocate_handle();
+        self.slots[handle.index as usize].entity = Some(EntityRecord {
+            view: EntityView { handle, pos, size },
+            data,
+            footprint,
+            positions: smallvec::smallvec![0; footprint.len()],
+            outside_at: 0,
+            visited: Default::default(),
+        });
+        self.insert(handle.index);
+        Ok(handle)
+    }
+
+    /// Removes the entity and hands its payload back.
+    pub fn remove(&mut self, id: EntityHandle) -> anyhow::Result<T, Error> {
+        self.record(id)?;
+        self.remove_from_cells(id.index);
+        Ok(self.free_handle(id))
+    }
+
+    /// Moves and resizes the entity. If the new bounds are rejected the entity
+    /// stays where it was.
+    ///
+    /// Only the cells the entity leaves or enters are touched; staying within
+    /// the same cells touches none. See [`SpatialHash::update_stats`].
+    pub fn update(
+        &mut self,
+        id: EntityHandle,
+        pos: Vec2<N>,
+        size: Vec2<N>,
+    ) -> anyhow::Result<(), Error> {
+        let old_footprint = self.record(id)?.footprint;
+        let footprint = self.footprint(&pos, &size)?;
+        if footprint == old_footprint {
+            self.update_stats.unchanged += 1;
+        } else {
+            self.move_cells(&old_footprint, &footprint, id.index);
+        }
+        let record = self.slots[id.index as usize]
+            .entity
+            .as_mut()
+            .expect("record checked above");
+        record.view.pos = pos;
+        record.view.size = size;
+        record.footprint = footprint;
+        Ok(())
+    }
+
+    pub fn set_position(&mut self, id: EntityHandle, pos: Vec2<N>) -> anyhow::Result<(), Error> {
+        let size = self.record(id)?.view.size.clone();
+        self.update(id, pos, size)
+    }
+
+    pub fn set_size(&mut self, id: EntityHandle, size: Vec2<N>) -> anyhow::Result<(), Error> {
+        let pos = self.record(id)?.view.pos.clone();
+        self.update(id, pos, size)
+    }
+
+    pub fn translate(&mut self, id: EntityHandle, delta: Vec2<N>) -> anyhow::Result<(), Error> {
+        let view = &self.record(id)?.view;
+        let (Some(x), Some(y)) = (
+            view.pos.x.checked_add(delta.x),
+            view.pos.y.checked_add(delta.y),
+        ) else {
+            return Err(Error::OutOfBounds);
+        };
+        let size = view.size.clone();
+        self.update(id, Vec2::new(x, y), size)
+    }
+
+    pub fn get(&self, id: EntityHandle) -> Option<&EntityView<N>> {
+        self.record(id).ok().map(|record| &record.view)
+    }
+
+    pub fn data(&self, id: EntityHandle) -> Option<&T> {
+        self.record(id).ok().map(|record| &record.data)
+    }
+
+    pub fn data_mut(&mut self, id: EntityHandle) -> Option<&mut T> {
+        self.record(id).ok()?;
+        self.slots[id.index as usize]
+            .entity
+            .as_mut()
+            .map(|record| &mut record.data)
+    }
+
+    /// This doubles the size of the entity to search around it. Fails with
+    /// [`Error::OutOfBounds`] if the doubled box does not fit into `N`.
+    pub fn find_nearest(&self, id: EntityHandle) -> anyhow::Result<HashSet<EntityHandle>, Error> {
+        let mut clients = HashSet::new();
+        self.nearest_hits(id, &mut self.visited(None), |handle| {
+            clients.insert(handle);
+        })?;
+        Ok(clients)
+    }
+
+    /// Same search as [`SpatialHash::find_nearest`], but pairs every hit with its payload.
+    pub fn find_nearest_with_data(
+        &self,
+        id: EntityHandle,
+    ) -> anyhow::Result<Vec<(&EntityHandle, &T)>, Error> {
+        let found = self.find_nearest(id)?;
+        Ok(found
+            .iter()
+            .map(|handle| self.entry(handle.index))
+            .collect())
+    }
+
+    /// Returns every entity whose bounding box overlaps the rectangle from `min`
+    /// to `max`. Parts of the rectangle outside of the grid are ignored.
+    pub fn query_rect(&self, min: Vec2<N>, max: Vec2<N>) -> Vec<EntityHandle> {
+        let mut found = Vec::new();
+        self.rect_hits(&min, &max, &mut self.visited(None), |handle| {
+            found.push(handle)
+        });
+        found
+    }
+
+    /// Returns every entity whose bounding box lies within `radius` of `center`.
+    pub fn query_radius(&self, center: Vec2<N>, radius: N::Float) -> Vec<EntityHandle> {
+        let mut found = Vec::new();
+        self.radius_hits(&center, radius, &mut self.visited(None), |handle, _| {
+            found.push(handle)
+        });
+        found
     }
 
-    pub fn remove(self: &mut Self, start: &Cell, end: &Cell, id: u32) -> anyhow::Result<()> {
+    /// Like [`SpatialHash::query_radius`], but ordered by distance, closest first.
+    pub fn query_radius_sorted(
+        &self,
+        center: Vec2<N>,
+        radius: N::Float,
+    ) -> Vec<(EntityHandle, N::Float)> {
+        let mut hits = Vec::new();
+        self.radius_hits(
+            &center,
+            radius,
+            &mut self.visited(None),
+            |handle, distance| hits.push((handle, distance)),
+        );
+        hits.sort_by(|a, b| a.1.total_cmp(&b.1));
+        hits
+    }
+
+    /// Returns the `k` entities closest to `point` with their distance, closest first.
+    pub fn k_nearest(&self, point: Vec2<N>, k: usize) -> Vec<(EntityHandle, N::Float)> {
+        self.k_nearest_within(point, k, N::Float::INFINITY)
+    }
+
+    /// Like [`SpatialHash::k_nearest`], but ignores everything further away than `max_dist`.
+    pub fn k_nearest_within(
+        &self,
+        point: Vec2<N>,
+        k: usize,
+        max_dist: N::Float,
+    ) -> Vec<(EntityHandle, N::Float)> {
+        let mut candidates = Vec::new();
+        self.nearest_k(
+            &point,
+            k,
+            max_dist,
+            &mut self.visited(None),
+            &mut candidates,
+        );
+        candidates
+    }
+
+    pub fn update_stats(&self) -> UpdateStats {
+        self.update_stats
+    }
+
+    pub fn reset_update_stats(&mut self) {
+        self.update_stats = UpdateStats::default();
+    }
+
+    /// Returns true if `id` still refers to a live entity.
+    pub fn contains(&self, id: EntityHandle) -> bool {
+        self.record(id).is_ok()
+    }
+
+    fn record(&self, id: EntityHandle) -> anyhow::Result<&EntityRecord<T, N>, Error> {
+        match self.slots.get(id.index as usize) {
+            Some(Slot {
+                generation,
+                entity: Some(record),
+            }) if *generation == id.generation => Ok(record),
+            _ => Err(Error::StaleHandle),
+        }
+    }
+
+    /// Calls `hit` with everything in the cells around the entity, searching
+    /// an area twice its size.
+    fn nearest_hits(
+        &self,
+        id: EntityHandle,
+        seen: &mut impl Seen,
+        mut hit: impl FnMut(EntityHandle),
+    ) -> anyhow::Result<(), Error> {
+        let view = &self.record(id)?.view;
+        let doubled = |size: N| size.checked_mul(N::TWO);
+        let (start_pos, end_pos) = match (doubled(view.size.x), doubled(view.size.y)) {
+            (Some(x), Some(y)) => Self::get_start_and_end(&view.pos, &Vec2::new(x, y)),
+            _ => None,
+        }
+        .ok_or(Error::OutOfBounds)?;
+
+        if let Some((start_idx, end_idx)) = self.clamped_cell_range(&start_pos, &end_pos) {
+            self.for_each_bucket(&start_idx, &end_idx, |vec| {
+                for &index in vec {
+                    if seen.first_visit(index) {
+                        hit(self.handle_at(index));
+                    }
+                }
+            });
+        }
+        // the outside bucket has no cells to narrow it down, so check the boxes
+        for &index in &self.outside {
+            if self.view_at(index).overlaps(&start_pos, &end_pos) && seen.first_visit(index) {
+                hit(self.handle_at(index));
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `hit` with every entity overlapping the rectangle from `min` to `max`.
+    fn rect_hits(
+        &self,
+        min: &Vec2<N>,
+        max: &Vec2<N>,
+        seen: &mut impl Seen,
+        mut hit: impl FnMut(EntityHandle),
+    ) {
+        let mut check = |index: u32| {
+            // entities spanning several cells show up once per cell
+            if !seen.first_visit(index) {
+                return;
+            }
+            let view = self.view_at(index);
+            if view.overlaps(min, max) {
+                hit(view.handle);
+            }
+        };
+
+        if let Some((start, end)) = self.clamped_cell_range(min, max) {
+            self.for_each_bucket(&start, &end, |vec| {
+                vec.iter().for_each(|&index| check(index));
+            });
+        }
+        self.outside.iter().for_each(|&index| check(index));
+    }
+
+    /// Fills `candidates` with the `k` entities closest to `point`, closest first.
+    fn nearest_k(
+        &self,
+        point: &Vec2<N>,
+        k: usize,
+        max_dist: N::Float,
+        seen: &mut impl Seen,
+        candidates: &mut Vec<(EntityHandle, N::Float)>,
+    ) {
+        if k == 0 {
+            return;
+        }
+        for &index in &self.outside {
+            self.collect_nearest(index, point, max_dist, seen, candidates);
+        }
+        let Some((first, last)) = self.extent else {
+            candidates.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+            candidates.truncate(k);
+            return;
+        };
+        let center = self.clamp_cell(Cell::unchecked(point, self));
+        let mut visited = 0;
+        // ring bounds are kept in i64, they may reach one past the last `i32` cell
+        let (first_col, last_col) = (i64::from(first.col), i64::from(last.col));
+        let (first_row, last_row) = (i64::from(first.row), i64::from(last.row));
+        let cell = |col: i64, row: i64| Cell {
+            col: col as i32,
+            row: row as i32,
+        };
+
+        // search square rings of cells around the centre cell, ring 0 being the cell itself
+        for ring in 0i64.. {
+            let (min_col, max_col) = (i64::from(center.col) - ring, i64::from(center.col) + ring);
+            let (min_row, max_row) = (i64::from(center.row) - ring, i64::from(center.row) + ring);
+            if visited > self.occupied_cells() {
+                // sparse grid, going through the occupied cells is cheaper than more rings
+                for (_, vec) in self.buckets() {
+                    for &index in vec {
+                        self.collect_nearest(index, point, max_dist, seen, candidates);
+                    }
+                }
+                break;
+            }
+            for row in min_row.max(first_row)..=max_row.min(last_row) {
+                if row == min_row || row == max_row {
+                    for col in min_col.max(first_col)..=max_col.min(last_col) {
+                        visited += 1;
+                        self.collect_nearest_in(cell(col, row), point, max_dist, seen, candidates);
+                    }
+                } else {
+                    for col in [min_col, max_col] {
+                        if (first_col..=last_col).contains(&col) {
+                            visited += 1;
+                            self.collect_nearest_in(
+                                cell(col, row),
+                                point,
+                                max_dist,
+                                seen,
+                                candidates,
+                            );
+                        }
+                    }
+                }
+            }
+
+            if min_col <= first_col
+                && min_row <= first_row
+                && max_col >= last_col
+                && max_row >= last_row
+            {
+                break;
+            }
+            // anything in a cell we have not visited yet is at least this far away.
+            // Beyond the edges of the grid there are no cells left to visit, anything
+            // reaching out there was already checked through the outside bucket.
+            let border = |reached_edge: bool, distance: N::Float| {
+                if reached_edge {
+                    N::Float::INFINITY
+                } else {
+                    distance
+                }
+            };
+            let low = self.cell_corner(min_col, min_row);
+            let high = self.cell_corner(max_col + 1, max_row + 1);
+            let point = point.to_float();
+            let left = border(min_col <= first_col, point.x - low.x);
+            let right = border(max_col >= last_col, high.x - point.x);
+            let bottom = border(min_row <= first_row, point.y - low.y);
+            let top = border(max_row >= last_row, high.y - point.y);
+            let unvisited = left.min(right).min(bottom).min(top);
+            if unvisited > max_dist {
+                break;
+            }
+            if candidates.len() >= k {
+                candidates.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+                if candidates[k - 1].1 <= unvisited {
+                    break;
+                }
+            }
+        }
+        candidates.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+        candidates.truncate(k);
+    }
+
+    /// Calls `hit` with every entity within `radius` of `center` and its distance.
+    fn radius_hits(
+        &self,
+        center: &Vec2<N>,
+        radius: N::Float,
+        seen: &mut impl Seen,
+        mut hit: impl FnMut(EntityHandle, N::Float),
+    ) {
+        let mut check = |vec: &[u32]| {
+            for &index in vec {
+                if !seen.first_visit(index) {
+                    continue;
+                }
+                let view = self.view_at(index);
+                let distance = view.distance_to(center);
+                if distance <= radius {
+                    hit(view.handle, distance);
+                }
+            }
+        };
+        check(&self.outside);
+        let Some((start, end)) = self.radius_cell_range(center, radius) else {
+            return;
+        };
+        if self.is_sparse(&start, &end) {
+            self.for_each_bucket(&start, &end, check);
+            return;
+        }
+
+        for row in start.row..=end.row {
+            // only walk the columns of this row that the circle actually reaches
+            let row_min = self.cell_min(&Cell { col: 0, row }).y;
+            let (low, high) = (row_min.to_float(), (row_min + self.cell_size.y).to_float());
+            let c = center.to_float();
+            let dy = (low - c.y).max(c.y - high).max(N::Float::ZERO);
+            let half_width = (radius * radius - dy * dy).max(N::Float::ZERO).sqrt();
+            let first = Cell::unchecked(&Vec2::new(N::floor_from(c.x - half_width), row_min), self);
+            let last = Cell::unchecked(&Vec2::new(N::ceil_from(c.x + half_width), row_min), self);
+
+            for col in first.col.clamp(start.col, end.col)..=last.col.clamp(start.col, end.col) {
+                if let Some(vec) = self.bucket(&Cell { col, row }) {
+                    check(vec);
+                }
+            }
+        }
+    }
+
+    fn collect_nearest_in(
+        &self,
+        cell: Cell,
+        point: &Vec2<N>,
+        max_dist: N::Float,
+        seen: &mut impl Seen,
+        candidates: &mut Vec<(EntityHandle, N::Float)>,
+    ) {
+        if let Some(vec) = self.bucket(&cell) {
+            for &index in vec {
+                self.collect_nearest(index, point, max_dist, seen, candidates);
+            }
+        }
+    }
+
+    fn collect_nearest(
+        &self,
+        index: u32,
+        point: &Vec2<N>,
+        max_dist: N::Float,
+        seen: &mut impl Seen,
+        candidates: &mut Vec<(EntityHandle, N::Float)>,
+    ) {
+        if !seen.first_visit(index) {
+            return;
+        }
+        let view = self.view_at(index);
+        let distance = view.distance_to(point);
+        if distance <= max_dist {
+            candidates.push((view.handle, distance));
+        }
+    }
+
+    fn entry(&self, index: u32) -> (&EntityHandle, &T) {
+        let record = self.record_at(index);
+        (&record.view.handle, &record.data)
+    }
+
+    fn view_at(&self, index: u32) -> &EntityView<N> {
+        &self.record_at(index).view
+    }
+
+    fn record_at(&self, index: u32) -> &EntityRecord<T, N> {
+        self.slots[index as usize]
+            .entity
+            .as_ref()
+            .expect("cells only hold live entities")
+    }
+
+    fn handle_at(&self, index: u32) -> EntityHandle {
+        EntityHandle {
+            index,
+            generation: self.slots[index as usize].generation,
+        }
+    }
+
+    fn allocate_handle(&mut self) -> EntityHandle {
+        allocate_slot(&mut self.slots, &mut self.free)
+    }
+
+    fn free_handle(&mut self, id: EntityHandle) -> T {
+        let (record, reusable) = self.slots[id.index as usize].vacate();
+        if reusable {
+            self.free.push(id.index);
+        }
+        record.data
+    }
+
+    /// Takes the entity out of every bucket of its footprint.
+    fn remove_from_cells(&mut self, index: u32) {
+        self.thaw();
+        let footprint = self.record_at(index).footprint;
+        if footprint.outside {
+            self.remove_outside(index);
+        }
+        let Some((start, end)) = footprint.cells else {
+            return;
+        };
         for col in start.col..=end.col {
             for row in start.row..=end.row {
-                let cell = Cell { col, row };
-                if let Some(vec) = self.cells.get_mut(&cell) {
-                    vec.retain(|&other_id| other_id != id);
-                    if vec.is_empty() {
-                        self.cells.remove(&cell);
+                self.remove_from_bucket(Cell { col, row }, index);
+            }
+        }
+    }
+
+    /// Appends the entity to the bucket of the cell, which has to be part of
+    /// its footprint, and remembers where.
+    fn push_to_bucket(&mut self, cell: Cell, index: u32) {
+        let vec = bucket_with_room(&mut self.cells, &mut self.pool, cell, 1);
+        vec.push(index);
+        *bucket_position(&mut self.slots, index, &cell) = vec.len() as u32 - 1;
+    }
+
+    /// Takes the entity out of the bucket of the cell, dropping the bucket once
+    /// it runs empty.
+    fn remove_from_bucket(&mut self, cell: Cell, index: u32) {
+        let vec = self
+            .cells
+            .get_mut(&cell)
+            .expect("entities sit in every cell of their footprint");
+        swap_out(&mut self.slots, vec, &cell, index);
+        if vec.is_empty() {
+            self.remove_bucket(&cell);
+        }
+    }
+
+    /// Drops the (empty) bucket of the cell, keeping it for later if it owns heap memory.
+    fn remove_bucket(&mut self, cell: &Cell) {
+        if let Some(bucket) = self.cells.remove(cell)
+            && bucket.spilled()
+        {
+            self.pool.push(bucket);
+        }
+    }
+
+    fn push_outside(&mut self, index: u32) {
+        record_mut(&mut self.slots, index).outside_at = self.outside.len() as u32;
+        self.outside.push(index);
+    }
+
+    fn remove_outside(&mut self, index: u32) {
+        let at = record_mut(&mut self.slots, index).outside_at as usize;
+        self.outside.swap_remove(at);
+        if let Some(&moved) = self.outside.get(at) {
+            record_mut(&mut self.slots, moved).outside_at = at as u32;
+        }
+    }
+
+    fn bucket(&self, cell: &Cell) -> Option<&[u32]> {
+        match &self.frozen {
+            Some(sorted) => sorted.get(cell),
+            None => self.cells.get(cell).map(Bucket::as_slice),
+        }
+    }
+
+    /// Every occupied cell with its bucket.
+    fn buckets(&self) -> impl Iterator<Item = (Cell, &[u32])> + '_ {
+        let (sorted, stored) = match &self.frozen {
+            Some(sorted) => (Some(sorted.iter()), None),
+            None => {
+                let stored = self.cells.iter().map(|(cell, vec)| (cell, vec.as_slice()));
+                (None, Some(stored))
+            }
+        };
+        sorted
+            .into_iter()
+            .flatten()
+            .chain(stored.into_iter().flatten())
+    }
+
+    fn occupied_cells(&self) -> usize {
+        match &self.frozen {
+            Some(sorted) => sorted.len(),
+            None => self.cells.len(),
+        }
+    }
+
+    /// Moves the entity from the old to the new footprint, leaving the cells
+    /// both have in common alone.
+    fn move_cells(&mut self, old: &Footprint, new: &Footprint, index: u32) {
+        self.thaw();
+        if old.outside && !new.outside {
+            self.remove_outside(index);
+        }
+        if let Some((start, end)) = old.cells {
+            for col in start.col..=end.col {
+                for row in start.row..=end.row {
+                    let cell = Cell { col, row };
+                    if !new.covers(&cell) {
+                        self.remove_from_bucket(cell, index);
+                        self.update_stats.cells_left += 1;
+                    }
+                }
+            }
+        }
+
+        self.carry_positions(index, new);
+
+        if !old.outside && new.outside {
+            self.push_outside(index);
+        }
+        if let Some((start, end)) = new.cells {
+            self.grow_extent(&start, &end);
+            for col in start.col..=end.col {
+                for row in start.row..=end.row {
+                    let cell = Cell { col, row };
+                    if !old.covers(&cell) {
+                        self.push_to_bucket(cell, index);
+                        self.update_stats.cells_entered += 1;
                     }
                 }
             }
         }
+        self.update_stats.moved += 1;
+    }
+
+    /// Gives the entity its new footprint, carrying the positions in the cells
+    /// both footprints share over to the new layout.
+    fn carry_positions(&mut self, index: u32, new: &Footprint) {
+        let mut positions = std::mem::take(&mut self.spare_positions);
+        positions.clear();
+        positions.resize(new.len(), 0);
+        let record = record_mut(&mut self.slots, index);
+        let old = record.footprint;
+        if let Some((start, end)) = new.cells {
+            for col in start.col..=end.col {
+                for row in start.row..=end.row {
+                    let cell = Cell { col, row };
+                    if old.covers(&cell) {
+                        positions[new.offset(&cell)] = record.positions[old.offset(&cell)];
+                    }
+                }
+            }
+        }
+        self.spare_positions = std::mem::replace(&mut record.positions, positions);
+        record.footprint = *new;
+    }
+
+    /// Calls `f` with the bucket of every occupied cell from `start` to `end`.
+    fn for_each_bucket(&self, start: &Cell, end: &Cell, f: impl FnMut(&[u32])) {
+        self.buckets_in(*start, *end).for_each(f);
+    }
+
+    /// Buckets of the occupied cells from `start` to `end`, found lazily.
+    fn buckets_in(&self, start: Cell, end: Cell) -> impl Iterator<Item = &[u32]> + '_ {
+        let (occupied, range) = if self.is_sparse(&start, &end) {
+            let in_range = move |cell: &Cell| {
+                (start.col..=end.col).contains(&cell.col)
+                    && (start.row..=end.row).contains(&cell.row)
+            };
+            let occupied = self
+                .buckets()
+                .filter(move |(cell, _)| in_range(cell))
+                .map(|(_, vec)| vec);
+            (Some(occupied), None)
+        } else {
+            let range = (start.col..=end.col).flat_map(move |col| {
+                (start.row..=end.row).filter_map(move |row| self.bucket(&Cell { col, row }))
+            });
+            (None, Some(range))
+        };
+        occupied
+            .into_iter()
+            .flatten()
+            .chain(range.into_iter().flatten())
+    }
+
+    /// True if the range holds more cells than are occupied, which happens for
+    /// large queries in unbounded grids. Walking the occupied cells is cheaper then.
+    fn is_sparse(&self, start: &Cell, end: &Cell) -> bool {
+        cell_count(start, end) > self.occupied_cells() as u64
+    }
+
+    /// Lower corner of the cell in world coordinates.
+    fn cell_min(&self, cell: &Cell) -> Vec2<N> {
+        Vec2::new(
+            self.start.x + N::from_index(cell.col.into()) * self.cell_size.x,
+            self.start.y + N::from_index(cell.row.into()) * self.cell_size.y,
+        )
+    }
+
+    /// Lower corner of the cell at `col`, `row` in the float type, also for
+    /// indices one past the `i32` range where [`Self::cell_min`] would overflow.
+    fn cell_corner(&self, col: i64, row: i64) -> Vec2<N::Float> {
+        let (start, size) = (self.start.to_float(), self.cell_size.to_float());
+        Vec2::new(
+            start.x + N::Float::from_index(col) * size.x,
+            start.y + N::Float::from_index(row) * size.y,
+        )
+    }
+
+    /// Cells covered by the rectangle from `min` to `max`, cut down to the grid.
+    /// `None` if the rectangle is empty or lies completely outside.
+    fn clamped_cell_range(&self, min: &Vec2<N>, max: &Vec2<N>) -> Option<(Cell, Cell)> {
+        if min.x > max.x || min.y > max.y {
+            return None;
+        }
+        let (first, last) = self.extent?;
+        let start = Cell::unchecked(min, self);
+        let end = Cell::unchecked(max, self);
+        if end.col < first.col
+            || end.row < first.row
+            || start.col > last.col
+            || start.row > last.row
+        {
+            return None;
+        }
+        Some((self.clamp_cell(start), self.clamp_cell(end)))
+    }
+
+    /// Cells covered by the square around the circle, cut down to the grid.
+    fn radius_cell_range(&self, center: &Vec2<N>, radius: N::Float) -> Option<(Cell, Cell)> {
+        let c = center.to_float();
+        // integer grids round outwards, so the square still covers the circle
+        let min = Vec2::new(N::floor_from(c.x - radius), N::floor_from(c.y - radius));
+        let max = Vec2::new(N::ceil_from(c.x + radius), N::ceil_from(c.y + radius));
+        self.clamped_cell_range(&min, &max)
+    }
+
+    fn clamp_cell(&self, cell: Cell) -> Cell {
+        let Some((first, last)) = self.extent else {
+            return cell;
+        };
+        Cell {
+            col: cell.col.clamp(first.col, last.col),
+            row: cell.row.clamp(first.row, last.row),
+        }
+    }
+
+    fn in_bounds(&self, pos: &Vec2<N>) -> bool {
+        if self.num_cells.is_none() {
+            return pos.x.is_finite() && pos.y.is_finite();
+        }
+        // written so that NaN coordinates count as out of bounds
+        (self.start.x <= pos.x && pos.x < self.end.x)
+            && (self.start.y <= pos.y && pos.y < self.end.y)
+    }
+
+    fn footprint(&self, pos: &Vec2<N>, size: &Vec2<N>) -> anyhow::Result<Footprint, Error> {
+        let footprint = self.footprint_unchecked(pos, size)?;
+        match footprint.cells {
+            Some((start, end)) if cell_count(&start, &end) > MAX_FOOTPRINT_CELLS as u64 => {
+                Err(Error::FootprintTooLarge)
+            }
+            _ => Ok(footprint),
+        }
+    }
+
+    /// Footprint of the box under the bounds policy, however many cells it covers.
+    fn footprint_unchecked(
+        &self,
+        pos: &Vec2<N>,
+        size: &Vec2<N>,
+    ) -> anyhow::Result<Footprint, Error> {
+        // NaN and infinite boxes have no cell to go to, whatever the policy
+        if ![pos.x, pos.y, size.x, size.y].iter().all(|n| n.is_finite()) {
+            return Err(Error::OutOfBounds);
+        }
+        if size.x < N::ZERO || size.y < N::ZERO {
+            return Err(Error::NegativeSize);
+        }
+        let (start_pos, end_pos) = Self::get_start_and_end(pos, size).ok_or(Error::OutOfBounds)?;
+        if self.in_bounds(&start_pos) && self.in_bounds(&end_pos) {
+            // only unbounded grids reach cells whose index does not fit
+            let (Some(start), Some(end)) = (
+                Cell::fitting(&start_pos, self),
+                Cell::fitting(&end_pos, self),
+            ) else {
+                return Err(Error::OutOfBounds);
+            };
+            return Ok(Footprint {
+                cells: Some((start, end)),
+                outside: false,
+            });
+        }
+        if self.num_cells.is_none() {
+            // an unbounded grid has no edge to clamp to or overflow past
+            return Err(Error::OutOfBounds);
+        }
+        match self.bounds_policy {
+            BoundsPolicy::Reject => Err(Error::OutOfBounds),
+            BoundsPolicy::Clamp => {
+                let start = self.clamp_cell(Cell::unchecked(&start_pos, self));
+                let end = self.clamp_cell(Cell::unchecked(&end_pos, self));
+                Ok(Footprint {
+                    cells: Some((start, end)),
+                    outside: true,
+                })
+            }
+            BoundsPolicy::Overflow => Ok(Footprint {
+                cells: None,
+                outside: true,
+            }),
+        }
+    }
+
+    /// First and last point of the box that decide its cells, `None` if the
+    /// box does not fit into `N`.
+    fn get_start_and_end(pos: &Vec2<N>, size: &Vec2<N>) -> Option<(Vec2<N>, Vec2<N>)> {
+        let axis = |pos: N, size: N| {
+            if size.abs() <= N::EPSILON {
+                return Some((pos, pos));
+            }
+            let (min, max) = N::box_bounds(pos, size)?;
+            Some((min + N::NUDGE, max - N::NUDGE))
+        };
+        let (start_x, end_x) = axis(pos.x, size.x)?;
+        let (start_y, end_y) = axis(pos.y, size.y)?;
+        Some((Vec2::new(start_x, start_y), Vec2::new(end_x, end_y)))
+    }
+
+    /// Puts the entity into every bucket of its footprint.
+    fn insert(&mut self, index: u32) {
+        self.thaw();
+        let footprint = self.record_at(index).footprint;
+        if footprint.outside {
+            self.push_outside(index);
+        }
+        let Some((start, end)) = footprint.cells else {
+            return;
+        };
+        self.grow_extent(&start, &end);
+        for col in start.col..=end.col {
+            for row in start.row..=end.row {
+                self.push_to_bucket(Cell { col, row }, index);
+            }
+        }
+    }
+
+    /// Unbounded grids widen their extent to every cell that gets used.
+    fn grow_extent(&mut self, start: &Cell, end: &Cell) {
+        if self.num_cells.is_none() {
+            self.extent = Some(match self.extent {
+                Some((first, last)) => (
+                    Cell {
+                        col: first.col.min(start.col),
+                        row: first.row.min(start.row),
+                    },
+                    Cell {
+                        col: last.col.max(end.col),
+                        row: last.row.max(end.row),
+                    },
+                ),
+                None => (*start, *end),
+            });
+        }
+    }
+}
+
+/// Number of cells from `start` to `end`, saturated instead of overflowing.
+fn cell_count(start: &Cell, end: &Cell) -> u64 {
+    let cols = (end.col as i64 - start.col as i64 + 1).max(0) as u64;
+    let rows = (end.row as i64 - start.row as i64 + 1).max(0) as u64;
+    cols.saturating_mul(rows)
+}
+
+fn record_mut<T, N>(slots: &mut [Slot<EntityRecord<T, N>>], index: u32) -> &mut EntityRecord<T, N> {
+    slots[index as usize]
+        .entity
+        .as_mut()
+        .expect("cells only hold live entities")
+}
+
+/// Bucket of the cell with room for `additional` more entries. A bucket about
+/// to outgrow its inline storage moves into a pooled one instead of allocating.
+fn bucket_with_room<'a, S: CellStorage>(
+    cells: &'a mut S,
+    pool: &mut Vec<Bucket>,
+    cell: Cell,
+    additional: usize,
+) -> &'a mut Bucket {
+    let vec = cells.get_or_create(cell);
+    if !vec.spilled()
+        && vec.len() + additional > vec.inline_size()
+        && let Some(mut pooled) = pool.pop()
+    {
+        pooled.extend_from_slice(vec);
+        *vec = pooled;
+    }
+    vec.reserve(additional);
+    vec
+}
+
+/// Swaps the entity out of `vec`, the bucket of `cell`; the last entry of the
+/// bucket takes its place.
+fn swap_out<T, N>(
+    slots: &mut [Slot<EntityRecord<T, N>>],
+    vec: &mut Bucket,
+    cell: &Cell,
+    index: u32,
+) {
+    let at = *bucket_position(slots, index, cell) as usize;
+    vec.swap_remove(at);
+    if let Some(&moved) = vec.get(at) {
+        *bucket_position(slots, moved, cell) = at as u32;
+    }
+}
+
+/// Where the entity sits inside the bucket of one of its footprint cells.
+fn bucket_position<'a, T, N>(
+    slots: &'a mut [Slot<EntityRecord<T, N>>],
+    index: u32,
+    cell: &Cell,
+) -> &'a mut u32 {
+    let record = record_mut(slots, index);
+    let offset = record.footprint.offset(cell);
+    &mut record.positions[offset]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::BTreeStorage;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn new_grid() {
+        let cell_size = Vec2::new(10.0, 10.0);
+        let start = Vec2::new(0.0, 0.0);
+        let end = Vec2::new(99.0, 99.0);
+        let res = SpatialHash::<()>::new(cell_size, start, end);
+        assert!(res.is_ok());
+        let grid = res.unwrap();
+        let num_cells = grid.num_cells.unwrap();
+        assert_eq!(num_cells.cols, 10);
+        assert_eq!(num_cells.rows, 10);
+    }
+
+    #[test]
+    fn grid_where_cell_size_and_bounds_are_not_dividable() {
+        let cell_size = Vec2::new(3.0, 3.0);
+        let start = Vec2::new(0.0, 0.0);
+        let end = Vec2::new(99.0, 99.0);
+        let res = SpatialHash::<()>::new(cell_size, start, end);
+        assert!(res.is_ok());
+        let grid = res.unwrap();
+        // 100 -> 102 / 3 = 34
+        let num_cells = grid.num_cells.unwrap();
+        assert_eq!(num_cells.cols, 34);
+        assert_eq!(num_cells.rows, 34);
+    }
+
+    #[test]
+    fn new_grid_where_num_cells_zero() {
+        let cell_size = Vec2::new(3.0, 3.0);
+        let start = Vec2::new(0.0, 0.0);
+        let end = Vec2::new(0.0, -1.0);
+        let res = SpatialHash::<()>::new(cell_size, start, end);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn create_entity() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let pos = Vec2::new(42.5, 42.5);
+        let expected_cell = Cell::new(&pos, &grid)?;
+        let size = Vec2::new(1.0, 1.0);
+
+        // Act
+        let res = grid.create(pos, size, ());
+
+        // Assert
+        assert!(res.is_ok());
+        let entity = res.unwrap();
+        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
+        assert_eq!(occupied_cells, 1, "expected 1 cell being occopied");
+        let res = grid.cells.get(&expected_cell);
+        assert!(res.is_some());
+        let id = res.unwrap()[0];
+        assert_eq!(entity.index, id);
+        Ok(())
+    }
+
+    #[test]
+    fn remove_entity() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let pos = Vec2::new(42.5, 42.5);
+        let expected_cell = Cell::new(&pos, &grid)?;
+        let size = Vec2::new(1.0, 1.0);
+        let entity = grid.create(pos, size, ())?;
+
+        // Act
+        grid.remove(entity)?;
+
+        // Assert
+        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
+        assert_eq!(occupied_cells, 0, "expected 0 cell being occopied");
+        let res = grid.cells.get(&expected_cell);
+        assert!(res.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn update_entity() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let pos = Vec2::new(42.5, 42.5);
+        let expected_none_cell = Cell::new(&pos, &grid)?;
+        let size = Vec2::new(1.0, 1.0);
+        let entity = grid.create(pos, size, ()).unwrap();
+        let new_pos = Vec2::new(41.5, 41.5);
+        let expected_cell = Cell::new(&new_pos, &grid)?;
+
+        // Act
+        grid.set_position(entity, new_pos.clone())?;
+
+        // Assert
+        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
+        assert_eq!(occupied_cells, 1, "expected 1 cell being occopied");
+        let res = grid.cells.get(&expected_none_cell);
+        assert!(res.is_none());
+        let res = grid.cells.get(&expected_cell);
+        assert!(res.is_some());
+        let id = res.unwrap()[0];
+        assert_eq!(entity.index, id);
+        assert_eq!(grid.get(entity).unwrap().pos(), &new_pos);
+        Ok(())
+    }
+
+    // New tests for find_nearest
+    #[test]
+    fn find_nearest_single_entity() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let pos = Vec2::new(42.5, 42.5);
+        let size = Vec2::new(1.0, 1.0);
+
+        // Act
+        let entity = grid.create(pos, size, ())?;
+        let found = grid.find_nearest(entity)?;
+
+        // Assert: should find itself only
+        assert_eq!(found.len(), 1);
+        assert!(found.contains(&entity));
+        Ok(())
+    }
+
+    #[test]
+    fn find_nearest_includes_neighbor_when_large() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        // create a larger entity that spans multiple cells
+        let pos_a = Vec2::new(42.5, 42.5);
+        let size_a = Vec2::new(3.0, 3.0); // spans approx cols 41..=43
+        let entity_a = grid.create(pos_a, size_a, ())?;
+
+        // create a nearby entity inside that span
+        let pos_b = Vec2::new(43.5, 42.5);
+        let size_b = Vec2::new(1.0, 1.0);
+        let entity_b = grid.create(pos_b, size_b, ())?;
+
+        // Act
+        let found = grid.find_nearest(entity_a)?;
+
+        // Assert: should find both ids (self and neighbor)
+        assert!(found.contains(&entity_a), "should contain self id");
+        assert!(found.contains(&entity_b), "should contain neighbor id");
+        assert_eq!(found.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn find_nearest_does_not_include_far_entities() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let pos_a = Vec2::new(42.5, 42.5);
+        let size_a = Vec2::new(1.0, 1.0);
+        let entity_a = grid.create(pos_a, size_a, ())?;
+
+        // create a far away entity
+        let pos_far = Vec2::new(50.5, 50.5);
+        let size_far = Vec2::new(1.0, 1.0);
+        let entity_far = grid.create(pos_far, size_far, ())?;
+
+        // Act
+        let found = grid.find_nearest(entity_a)?;
+
+        // Assert: should only find the near entity (self)
+        assert!(found.contains(&entity_a));
+        assert!(!found.contains(&entity_far));
+        assert_eq!(found.len(), 1);
+        Ok(())
+    }
+
+    // TODO change Entity to invalid position
+    // TODO update two Entities that are on the same spot at the beginning
+    #[test]
+    fn create_entity_no_size() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let pos = Vec2::new(42.0, 42.0);
+        let expected_cell = Cell::new(&pos, &grid)?;
+        dbg! {&expected_cell};
+        let size = Vec2::new(0.0, 0.0);
+
+        // Act
+        let res = grid.create(pos, size, ());
+
+        // Assert
+        assert!(res.is_ok());
+        let entity = res.unwrap();
+        dbg! {&entity};
+        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
+        assert_eq!(occupied_cells, 1, "expected 1 cell being occopied");
+        let res = grid.cells.get(&expected_cell);
+        assert!(res.is_some());
+        let id = res.unwrap()[0];
+        assert_eq!(entity.index, id);
+        Ok(())
+    }
+
+    #[test]
+    fn create_two_entities() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let pos = Vec2::new(42.5, 42.5);
+        let pos2 = Vec2::new(30.5, 30.5);
+        let expected_cell = Cell::new(&pos, &grid)?;
+        let expected_cell2 = Cell::new(&pos2, &grid)?;
+        let size = Vec2::new(1.0, 1.0);
+
+        // Act
+        let res = grid.create(pos, size.clone(), ());
+        let res2 = grid.create(pos2, size, ());
+
+        // Assert
+        assert!(res.is_ok());
+        assert!(res2.is_ok());
+        let entity = res.unwrap();
+        let entity2 = res2.unwrap();
+        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
+        assert_eq!(occupied_cells, 2, "expected 2 cell being occopied");
+        let res = grid.cells.get(&expected_cell);
+        let res2 = grid.cells.get(&expected_cell2);
+        assert!(res.is_some());
+        assert!(res2.is_some());
+        let id = res.unwrap()[0];
+        let id2 = res2.unwrap()[0];
+        assert_eq!(entity.index, id);
+        assert_eq!(entity2.index, id2);
+        Ok(())
+    }
+
+    #[test]
+    fn create_two_overlapping_entities() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let pos = Vec2::new(42.5, 42.5);
+        let pos2 = Vec2::new(42.5, 42.5);
+        let expected_cell = Cell::new(&pos, &grid)?;
+        let size = Vec2::new(1.0, 1.0);
+
+        // Act
+        let res = grid.create(pos, size.clone(), ());
+        let res2 = grid.create(pos2, size, ());
+
+        // Assert
+        assert!(res.is_ok());
+        assert!(res2.is_ok());
+        let entity = res.unwrap();
+        let entity2 = res2.unwrap();
+        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
+        assert_eq!(occupied_cells, 2, "expected 2 cell being occopied");
+        let res = grid.cells.get(&expected_cell);
+        assert!(res.is_some());
+        let id = res.unwrap()[0];
+        let id2 = res.unwrap()[1];
+        assert_eq!(entity.index, id);
+        assert_eq!(entity2.index, id2);
+        Ok(())
+    }
+
+    #[test]
+    fn create_bigger_entity() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let pos = Vec2::new(42.5, 42.5);
+        let expected_cell = Cell::new(&pos, &grid)?;
+        let size = Vec2::new(3.0, 3.0);
+
+        // Act
+        let res = grid.create(pos, size, ());
+
+        // Assert
+        assert!(res.is_ok());
+        let entity = res.unwrap();
+        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
+        // checks if every
+        assert!(
+            grid.cells
+                .values()
+                .all(|v| v.iter().all(|&id| id == entity.index))
+        );
+        assert_eq!(occupied_cells, 9, "expected 3x3 cells being occopied");
+        let res = grid.cells.get(&expected_cell);
+        assert!(res.is_some());
+        let id = res.unwrap()[0];
+        assert_eq!(entity.index, id);
+        // check if every identity is the same
+        Ok(())
+    }
+
+    #[test]
+    fn removed_slot_is_reused_with_new_generation() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(1.0, 1.0);
+        let entity = grid.create(Vec2::new(42.5, 42.5), size.clone(), ())?;
+        let old_handle = entity;
+        grid.remove(entity)?;
+
+        // Act
+        let reused = grid.create(Vec2::new(10.5, 10.5), size, ())?;
+
+        // Assert
+        assert_eq!(reused.index(), old_handle.index());
+        assert_ne!(reused.generation(), old_handle.generation());
+        assert!(!grid.contains(old_handle));
+        assert!(grid.contains(reused));
+        Ok(())
+    }
+
+    #[test]
+    fn stale_entity_is_rejected() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(1.0, 1.0);
+        let entity = grid.create(Vec2::new(42.5, 42.5), size.clone(), ())?;
+        grid.remove(entity)?;
+        let other = grid.create(Vec2::new(42.5, 42.5), size.clone(), ())?;
+
+        // Act + Assert
+        assert!(matches!(grid.find_nearest(entity), Err(Error::StaleHandle)));
+        assert!(matches!(grid.remove(entity), Err(Error::StaleHandle)));
+        assert!(matches!(
+            grid.update(entity, Vec2::new(1.5, 1.5), size),
+            Err(Error::StaleHandle)
+        ));
+        assert!(grid.get(entity).is_none());
+        // the stale calls must not have touched the new occupant of the slot
+        let found = grid.find_nearest(other)?;
+        assert_eq!(found.len(), 1);
+        assert!(found.contains(&other));
+        Ok(())
+    }
+
+    #[test]
+    fn translate_and_resize_entity() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let entity = grid.create(Vec2::new(42.5, 42.5), Vec2::new(1.0, 1.0), ())?;
+
+        // Act
+        grid.translate(entity, Vec2::new(2.0, -1.0))?;
+        grid.set_size(entity, Vec2::new(3.0, 3.0))?;
+
+        // Assert
+        let view = grid.get(entity).unwrap();
+        assert_eq!(view.handle(), entity);
+        assert_eq!(view.pos(), &Vec2::new(44.5, 41.5));
+        assert_eq!(view.size(), &Vec2::new(3.0, 3.0));
+        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
+        assert_eq!(occupied_cells, 9, "expected 3x3 cells being occopied");
+        assert!(grid.cells.contains_key(&Cell { col: 44, row: 41 }));
+        Ok(())
+    }
+
+    #[test]
+    fn update_only_touches_changed_cells() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let entity = grid.create(Vec2::new(42.5, 42.5), Vec2::new(2.0, 2.0), ())?;
+        let other = grid.create(Vec2::new(43.5, 43.5), Vec2::new(1.0, 1.0), ())?;
+
+        // Act
+        grid.translate(entity, Vec2::new(0.2, 0.2))?;
+        let nudged = grid.update_stats();
+        grid.translate(entity, Vec2::new(1.0, 0.0))?;
+
+        // Assert
+        assert_eq!(
+            nudged,
+            UpdateStats {
+                unchanged: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            grid.update_stats(),
+            UpdateStats {
+                unchanged: 1,
+                moved: 1,
+                cells_left: 3,
+                cells_entered: 3,
+            }
+        );
+        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
+        assert_eq!(occupied_cells, 10);
+        assert!(!grid.cells.contains_key(&Cell { col: 41, row: 41 }));
+        // a shared cell was not touched, so the order in it is unchanged
+        assert_eq!(
+            grid.cells
+                .get(&Cell { col: 43, row: 43 })
+                .map(Bucket::as_slice),
+            Some([entity.index, other.index].as_slice())
+        );
+        grid.reset_update_stats();
+        assert_eq!(grid.update_stats(), UpdateStats::default());
+        Ok(())
+    }
+
+    #[test]
+    fn bucket_positions_follow_swap_removals() -> anyhow::Result<(), Error> {
+        // Arrange: a dense cluster, partly reaching outside the grid
+        let mut grid = create_grid().with_bounds_policy(BoundsPolicy::Overflow);
+        let mut handles = Vec::new();
+        for i in 0..60 {
+            let pos = Vec2::new(96.0 + (i % 7) as f32 * 0.5, 50.0 + (i % 5) as f32 * 0.5);
+            handles.push(grid.create(pos, Vec2::new(1.5, 1.5), ())?);
+        }
+
+        // Act
+        for (i, handle) in handles.iter().enumerate() {
+            match i % 3 {
+                0 => drop(grid.remove(*handle)?),
+                1 => grid.translate(*handle, Vec2::new(1.0, 0.5))?,
+                _ => grid.set_size(*handle, Vec2::new(0.5, 2.5))?,
+            }
+        }
+        let removed = grid.remove_many(handles.iter().copied().skip(1).step_by(6));
+        let moved = handles
+            .iter()
+            .skip(2)
+            .step_by(3)
+            .map(|&handle| (handle, Vec2::new(97.0, 52.0), Vec2::new(1.0, 1.0)));
+        let moved = grid.update_many(moved);
+
+        // Assert
+        assert!(removed.iter().all(Result::is_ok));
+        assert!(moved.iter().all(Result::is_ok));
+        for (cell, vec) in grid.cells.iter() {
+            for (at, &index) in vec.iter().enumerate() {
+                assert_eq!(*bucket_position(&mut grid.slots, index, &cell), at as u32);
+            }
+        }
+        for (at, &index) in grid.outside.iter().enumerate() {
+            assert_eq!(grid.record_at(index).outside_at, at as u32);
+        }
+        let live = handles
+            .iter()
+            .filter(|&&handle| grid.contains(handle))
+            .count();
+        let found = grid.query_rect(Vec2::new(90.0, 45.0), Vec2::new(110.0, 60.0));
+        assert_eq!(found.len(), live);
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_move_keeps_entity_in_place() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let pos = Vec2::new(42.5, 42.5);
+        let cell = Cell::new(&pos, &grid)?;
+        let entity = grid.create(pos.clone(), Vec2::new(1.0, 1.0), ())?;
+
+        // Act
+        let res = grid.set_position(entity, Vec2::new(-5.0, -5.0));
+
+        // Assert
+        assert!(matches!(res, Err(Error::OutOfBounds)));
+        assert_eq!(grid.get(entity).unwrap().pos(), &pos);
+        assert_eq!(
+            grid.cells.get(&cell).map(Bucket::as_slice),
+            Some([entity.index].as_slice())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn find_nearest_with_data_returns_payloads() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = SpatialHash::new(
+            Vec2::new(1.0, 1.0),
+            Vec2::new(0.0, 0.0),
+            Vec2::new(99.0, 99.0),
+        )?;
+        let player = grid.create(Vec2::new(42.5, 42.5), Vec2::new(1.0, 1.0), "player")?;
+        let goblin = grid.create(Vec2::new(43.0, 42.5), Vec2::new(1.0, 1.0), "goblin")?;
+        grid.create(Vec2::new(80.5, 80.5), Vec2::new(1.0, 1.0), "dragon")?;
+
+        // Act
+        let mut found = grid.find_nearest_with_data(player)?;
+        found.sort_by_key(|(_, name)| **name);
+
+        // Assert
+        assert_eq!(found, vec![(&goblin, &"goblin"), (&player, &"player")]);
+        *grid.data_mut(goblin).unwrap() = "dead goblin";
+        assert_eq!(grid.remove(goblin)?, "dead goblin");
+        assert_eq!(grid.data(player), Some(&"player"));
+        Ok(())
+    }
+
+    #[test]
+    fn query_rect_finds_overlapping_entities() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let inside = grid.create(Vec2::new(10.5, 10.5), Vec2::new(1.0, 1.0), ())?;
+        let touching = grid.create(Vec2::new(15.0, 12.0), Vec2::new(2.0, 2.0), ())?;
+        let big = grid.create(Vec2::new(8.0, 8.0), Vec2::new(4.0, 4.0), ())?;
+        grid.create(Vec2::new(30.5, 30.5), Vec2::new(1.0, 1.0), ())?;
+
+        // Act
+        let mut found = grid.query_rect(Vec2::new(9.0, 9.0), Vec2::new(14.0, 14.0));
+
+        // Assert: the big entity spans several queried cells but shows up once
+        found.sort_by_key(|handle| handle.index());
+        assert_eq!(found, vec![inside, touching, big]);
+        Ok(())
+    }
+
+    #[test]
+    fn query_rect_drops_false_positives_in_shared_cells() -> anyhow::Result<(), Error> {
+        // Arrange
+        let cell_size = Vec2::new(10.0, 10.0);
+        let mut grid = SpatialHash::new(cell_size, Vec2::new(0.0, 0.0), Vec2::new(99.0, 99.0))?;
+        let near = grid.create(Vec2::new(2.0, 2.0), Vec2::new(1.0, 1.0), ())?;
+        // same cell as the query, but not overlapping it
+        grid.create(Vec2::new(8.0, 8.0), Vec2::new(1.0, 1.0), ())?;
+
+        // Act
+        let found = grid.query_rect(Vec2::new(0.0, 0.0), Vec2::new(4.0, 4.0));
+
+        // Assert
+        assert_eq!(found, vec![near]);
+        Ok(())
+    }
+
+    #[test]
+    fn query_rect_partially_outside_grid() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let corner = grid.create(Vec2::new(0.5, 0.5), Vec2::new(1.0, 1.0), ())?;
+        let far_corner = grid.create(Vec2::new(99.5, 99.5), Vec2::new(1.0, 1.0), ())?;
+
+        // Act
+        let low = grid.query_rect(Vec2::new(-50.0, -50.0), Vec2::new(2.0, 2.0));
+        let high = grid.query_rect(Vec2::new(98.0, 98.0), Vec2::new(500.0, 500.0));
+        let outside = grid.query_rect(Vec2::new(-50.0, -50.0), Vec2::new(-10.0, -10.0));
+        let inverted = grid.query_rect(Vec2::new(2.0, 2.0), Vec2::new(0.0, 0.0));
+
+        // Assert
+        assert_eq!(low, vec![corner]);
+        assert_eq!(high, vec![far_corner]);
+        assert!(outside.is_empty());
+        assert!(inverted.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn query_radius_filters_by_true_distance() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let center = Vec2::new(50.0, 50.0);
+        let close = grid.create(Vec2::new(52.5, 50.5), Vec2::new(1.0, 1.0), ())?;
+        // inside the bounding square of the circle, but not inside the circle
+        grid.create(Vec2::new(54.5, 54.5), Vec2::new(1.0, 1.0), ())?;
+        // centre is out of reach, but the box edge is not
+        let wide = grid.create(Vec2::new(50.0, 40.0), Vec2::new(2.0, 12.0), ())?;
+
+        // Act
+        let mut found = grid.query_radius(center, 5.0);
+
+        // Assert
+        found.sort_by_key(|handle| handle.index());
+        assert_eq!(found, vec![close, wide]);
+        Ok(())
+    }
+
+    #[test]
+    fn query_radius_sorted_orders_by_distance() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(0.0, 0.0);
+        let far = grid.create(Vec2::new(20.0, 24.0), size.clone(), ())?;
+        let near = grid.create(Vec2::new(21.0, 20.0), size.clone(), ())?;
+        let mid = grid.create(Vec2::new(17.0, 20.0), size.clone(), ())?;
+        grid.create(Vec2::new(20.0, 30.0), size, ())?;
+
+        // Act
+        let found = grid.query_radius_sorted(Vec2::new(20.0, 20.0), 4.0);
+
+        // Assert
+        assert_eq!(found, vec![(near, 1.0), (mid, 3.0), (far, 4.0)]);
+        Ok(())
+    }
+
+    #[test]
+    fn k_nearest_returns_closest_first() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(0.0, 0.0);
+        let a = grid.create(Vec2::new(50.0, 53.0), size.clone(), ())?;
+        let b = grid.create(Vec2::new(51.0, 50.0), size.clone(), ())?;
+        let c = grid.create(Vec2::new(40.0, 50.0), size.clone(), ())?;
+        grid.create(Vec2::new(90.0, 90.0), size.clone(), ())?;
+        grid.create(Vec2::new(5.0, 5.0), size, ())?;
+
+        // Act
+        let found = grid.k_nearest(Vec2::new(50.0, 50.0), 3);
+
+        // Assert
+        assert_eq!(found, vec![(b, 1.0), (a, 3.0), (c, 10.0)]);
         Ok(())
     }
 
-    pub fn update(self: &mut Self, mut entity: Entity) -> anyhow::Result<Entity, Error> {
-        self.remove(&entity.start, &entity.end, entity.id);
-        let (start_pos, end_pos) = Self::get_start_and_end(&entity.pos, &entity.size);
-        entity.start = Cell::new(&start_pos, &self)?;
-        entity.end = Cell::new(&end_pos, &self)?;
-        self.insert(&entity);
-        Ok(entity)
-    }
+    #[test]
+    fn k_nearest_checks_rings_beyond_the_first_hit() -> anyhow::Result<(), Error> {
+        // Arrange: the first hit is in the centre cells' corner, a closer one sits one ring out
+        let mut grid = SpatialHash::new(
+            Vec2::new(10.0, 10.0),
+            Vec2::new(0.0, 0.0),
+            Vec2::new(99.0, 99.0),
+        )?;
+        let size = Vec2::new(0.0, 0.0);
+        let corner = grid.create(Vec2::new(50.5, 50.5), size.clone(), ())?;
+        let neighbour = grid.create(Vec2::new(60.5, 59.0), size, ())?;
 
-    /// This doubles the size of the entity to search around it
-    pub fn find_nearest(self: &Self, entity: &Entity) -> anyhow::Result<HashSet<u32>, Error> {
-        let (start_pos, end_pos) = Self::get_start_and_end(&entity.pos, &(&entity.size * 2.0));
-        let start_idx = Cell::new(&start_pos, &self)?;
-        let end_idx = Cell::new(&end_pos, &self)?;
-        let mut clients = HashSet::new();
+        // Act
+        let found = grid.k_nearest(Vec2::new(59.0, 59.0), 1);
 
-        for col in start_idx.col..=end_idx.col {
-            for row in start_idx.row..=end_idx.row {
-                let cell = Cell { col, row };
-                if let Some(vec) = self.cells.get(&cell) {
-                    clients.extend(vec.iter().cloned());
-                }
-            }
-        }
-        Ok(clients)
+        // Assert
+        assert_eq!(found, vec![(neighbour, 1.5)]);
+        assert_eq!(grid.k_nearest(Vec2::new(59.0, 59.0), 5).len(), 2);
+        assert_eq!(grid.k_nearest(Vec2::new(59.0, 59.0), 5)[1].0, corner);
+        Ok(())
     }
 
-    fn get_start_and_end(pos: &Vec2, size: &Vec2) -> (Vec2, Vec2) {
-        let is_zero_x = size.x.abs() <= f32::EPSILON;
-        let is_zero_y = size.y.abs() <= f32::EPSILON;
-        // TODO replace 0.0001 with something sensible.
-        let start_half = pos - (size / 2.0) + 0.0001;
-        let start_pos = Vec2::new(
-            if is_zero_x { pos.x } else { start_half.x },
-            if is_zero_y { pos.y } else { start_half.y },
-        );
+    #[test]
+    fn k_nearest_within_respects_max_distance() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(1.0, 1.0);
+        let near = grid.create(Vec2::new(10.5, 10.5), size.clone(), ())?;
+        grid.create(Vec2::new(80.5, 80.5), size, ())?;
 
-        let end_half = pos + (size / 2.0) - 0.0001;
-        let end_pos = Vec2::new(
-            if is_zero_x { pos.x } else { end_half.x },
-            if is_zero_y { pos.y } else { end_half.y },
-        );
-        dbg! {&start_pos, &end_pos};
-        (start_pos, end_pos)
-    }
+        // Act
+        let found = grid.k_nearest_within(Vec2::new(10.5, 12.0), 2, 5.0);
+        let from_outside = grid.k_nearest(Vec2::new(-20.0, 10.5), 1);
 
-    fn insert(self: &mut Self, entity: &Entity) {
-        for col in entity.start.col..=entity.end.col {
-            for row in entity.start.row..=entity.end.row {
-                let cell = Cell { col, row };
-                self.cells.entry(cell).or_default().push(entity.id);
-            }
-        }
+        // Assert
+        assert_eq!(found, vec![(near, 1.0)]);
+        assert_eq!(from_outside, vec![(near, 30.0)]);
+        assert!(grid.k_nearest(Vec2::new(10.5, 12.0), 0).is_empty());
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::panic::{self, AssertUnwindSafe};
 
     #[test]
-    fn new_grid() {
-        let cell_size = Vec2::new(10.0, 10.0);
-        let start = Vec2::new(0.0, 0.0);
-        let end = Vec2::new(99.0, 99.0);
-        let res = SpatialHash::new(cell_size, start, end);
-        assert!(res.is_ok());
-        let grid = res.unwrap();
-        assert_eq!(grid.num_cells.cols, 10);
-        assert_eq!(grid.num_cells.rows, 10);
-    }
+    fn bounds_are_checked_per_axis() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(1.0, 1.0);
 
-    #[test]
-    fn grid_where_cell_size_and_bounds_are_not_dividable() {
-        let cell_size = Vec2::new(3.0, 3.0);
-        let start = Vec2::new(0.0, 0.0);
-        let end = Vec2::new(99.0, 99.0);
-        let res = SpatialHash::new(cell_size, start, end);
-        assert!(res.is_ok());
-        let grid = res.unwrap();
-        // 100 -> 102 / 3 = 34
-        assert_eq!(grid.num_cells.cols, 34);
-        assert_eq!(grid.num_cells.rows, 34);
+        // Act + Assert: x alone being fine must not let a bad y through
+        let below = grid.create(Vec2::new(50.0, -5.0), size.clone(), ());
+        let beyond = grid.create(Vec2::new(150.0, 50.0), size.clone(), ());
+        let reaching_out = grid.create(Vec2::new(99.9, 50.0), size.clone(), ());
+        assert!(matches!(below, Err(Error::OutOfBounds)));
+        assert!(matches!(beyond, Err(Error::OutOfBounds)));
+        assert!(matches!(reaching_out, Err(Error::OutOfBounds)));
+        assert!(grid.create(Vec2::new(99.4, 99.4), size, ()).is_ok());
+        assert!(
+            grid.cells
+                .iter()
+                .all(|(cell, _)| cell.col < 100 && cell.row < 100)
+        );
+        Ok(())
     }
 
     #[test]
-    fn new_grid_where_num_cells_zero() {
-        let cell_size = Vec2::new(3.0, 3.0);
-        let start = Vec2::new(0.0, 0.0);
-        let end = Vec2::new(0.0, -1.0);
-        let res = SpatialHash::new(cell_size, start, end);
-        assert!(res.is_err());
+    fn clamp_policy_stores_entities_in_edge_cells() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid().with_bounds_policy(BoundsPolicy::Clamp);
+        let size = Vec2::new(1.0, 1.0);
+
+        // Act
+        let beyond = grid.create(Vec2::new(150.5, 50.5), size.clone(), ())?;
+        let below = grid.create(Vec2::new(-20.5, -20.5), size.clone(), ())?;
+        let nan = grid.create(Vec2::new(f32::NAN, 50.5), size.clone(), ());
+        let infinite = grid.create(Vec2::new(f32::INFINITY, 50.5), size.clone(), ());
+        let endless = grid.create(Vec2::new(50.5, 50.5), Vec2::new(1.0, f32::INFINITY), ());
+
+        // Assert
+        assert!(matches!(nan, Err(Error::OutOfBounds)));
+        assert!(matches!(infinite, Err(Error::OutOfBounds)));
+        assert!(matches!(endless, Err(Error::OutOfBounds)));
+        assert_eq!(
+            grid.cells
+                .get(&Cell { col: 99, row: 50 })
+                .map(Bucket::as_slice),
+            Some([beyond.index].as_slice())
+        );
+        assert_eq!(
+            grid.cells
+                .get(&Cell { col: 0, row: 0 })
+                .map(Bucket::as_slice),
+            Some([below.index].as_slice())
+        );
+        assert_eq!(
+            grid.query_rect(Vec2::new(140.0, 40.0), Vec2::new(160.0, 60.0)),
+            vec![beyond]
+        );
+        assert_eq!(grid.query_radius(Vec2::new(-20.0, -20.0), 1.0), vec![below]);
+        assert_eq!(
+            grid.k_nearest(Vec2::new(-30.0, -20.5), 1),
+            vec![(below, 9.0)]
+        );
+        let hit = grid.raycast(Vec2::new(200.0, 50.5), Vec2::new(-1.0, 0.0), 100.0);
+        assert_eq!(hit.unwrap().handle, beyond);
+        assert!(
+            grid.query_rect(Vec2::new(95.0, 45.0), Vec2::new(99.0, 55.0))
+                .is_empty()
+        );
+        grid.remove(beyond)?;
+        assert!(!grid.cells.contains_key(&Cell { col: 99, row: 50 }));
+        assert!(grid.query_radius(Vec2::new(150.5, 50.5), 1.0).is_empty());
+        Ok(())
     }
 
     #[test]
-    fn create_entity() -> anyhow::Result<(), Error> {
+    fn overflow_policy_keeps_entities_in_outside_bucket() -> anyhow::Result<(), Error> {
         // Arrange
-        let mut grid = create_grid();
-        let pos = Vec2::new(42.5, 42.5);
-        let expected_cell = Cell::new(&pos, &grid)?;
-        let size = Vec2::new(1.0, 1.0);
+        let mut grid = create_grid().with_bounds_policy(BoundsPolicy::Overflow);
+        let size = Vec2::new(2.0, 2.0);
+        let inside = grid.create(Vec2::new(99.0, 50.0), size.clone(), ())?;
 
         // Act
-        let res = grid.create(pos, size);
+        let straddling = grid.create(Vec2::new(100.5, 50.0), size.clone(), ())?;
+        let far = grid.create(Vec2::new(500.0, 500.0), size, ())?;
 
         // Assert
-        assert!(res.is_ok());
-        let entity = res.unwrap();
         let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
-        assert_eq!(occupied_cells, 1, "expected 1 cell being occopied");
-        let res = grid.cells.get(&expected_cell);
-        assert!(res.is_some());
-        let id = res.unwrap()[0];
-        assert_eq!(entity.id, id);
+        assert_eq!(occupied_cells, 4, "only the inside entity takes up cells");
+        assert_eq!(grid.outside, vec![straddling.index, far.index]);
+        assert_eq!(
+            grid.query_rect(Vec2::new(499.0, 499.0), Vec2::new(501.0, 501.0)),
+            vec![far]
+        );
+        assert_eq!(
+            grid.k_nearest(Vec2::new(102.0, 50.0), 1),
+            vec![(straddling, 0.5)]
+        );
+        let mut found = grid.query_radius(Vec2::new(100.0, 50.0), 1.0);
+        found.sort_by_key(|handle| handle.index());
+        assert_eq!(found, vec![inside, straddling]);
+        assert!(grid.find_nearest(inside)?.contains(&straddling));
+        let pairs: Vec<_> = grid.overlapping_pairs().collect();
+        assert_eq!(pairs, vec![(straddling, inside)]);
+        let hit = grid.raycast(Vec2::new(500.0, 0.0), Vec2::new(0.0, 1.0), 1000.0);
+        assert_eq!(hit.unwrap().handle, far);
+
+        // moving back inside leaves the outside bucket
+        grid.set_position(straddling, Vec2::new(50.0, 50.0))?;
+        grid.remove(far)?;
+        assert!(grid.outside.is_empty());
+        assert_eq!(
+            grid.query_radius(Vec2::new(50.0, 50.0), 0.5),
+            vec![straddling]
+        );
         Ok(())
     }
 
     #[test]
-    fn remove_entity() -> anyhow::Result<(), Error> {
+    fn unbounded_grid_places_entities_anywhere() -> anyhow::Result<(), Error> {
         // Arrange
-        let mut grid = create_grid();
-        let pos = Vec2::new(42.5, 42.5);
-        let expected_cell = Cell::new(&pos, &grid)?;
-        let size = Vec2::new(1.0, 1.0);
-        grid.create(pos, size);
+        let mut grid = SpatialHash::unbounded(Vec2::new(4.0, 4.0))?;
+        let size = Vec2::new(2.0, 2.0);
 
         // Act
-        grid.remove(&expected_cell, &expected_cell, 0);
+        let negative = grid.create(Vec2::new(-10.0, -6.0), size.clone(), ())?;
+        let origin = grid.create(Vec2::new(1.0, 1.0), size.clone(), ())?;
+        let far = grid.create(Vec2::new(100_000.0, -50_000.0), size.clone(), ())?;
+        let nan = grid.create(Vec2::new(f32::NAN, 0.0), size, ());
 
         // Assert
-        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
-        assert_eq!(occupied_cells, 0, "expected 0 cell being occopied");
-        let res = grid.cells.get(&expected_cell);
-        assert!(res.is_none());
+        assert!(matches!(nan, Err(Error::OutOfBounds)));
+        assert!(grid.cells.contains_key(&Cell { col: -3, row: -2 }));
+        assert!(grid.cells.contains_key(&Cell {
+            col: 24_999,
+            row: -12_501
+        }));
+        assert_eq!(
+            grid.query_rect(Vec2::new(-12.0, -8.0), Vec2::new(-9.0, -5.0)),
+            vec![negative]
+        );
+        let mut everything = grid.query_rect(Vec2::new(-1e9, -1e9), Vec2::new(1e9, 1e9));
+        everything.sort_by_key(|handle| handle.index());
+        assert_eq!(everything, vec![negative, origin, far]);
+        assert_eq!(
+            grid.query_radius(Vec2::new(100_000.0, -50_002.0), 1.5),
+            vec![far]
+        );
+        let nearest = grid.k_nearest(Vec2::new(0.0, 0.0), 2);
+        let nearest: Vec<_> = nearest.into_iter().map(|(handle, _)| handle).collect();
+        assert_eq!(nearest, vec![origin, negative]);
+        assert_eq!(grid.k_nearest(Vec2::new(90_000.0, -45_000.0), 1)[0].0, far);
+        assert!(grid.find_nearest(origin)?.contains(&origin));
         Ok(())
     }
 
     #[test]
-    fn update_entity() -> anyhow::Result<(), Error> {
+    fn negative_sizes_are_refused() -> anyhow::Result<(), Error> {
         // Arrange
         let mut grid = create_grid();
-        let pos = Vec2::new(42.5, 42.5);
-        let expected_none_cell = Cell::new(&pos, &grid)?;
-        let size = Vec2::new(1.0, 1.0);
-        let mut entity = grid.create(pos, size).unwrap();
-        let new_pos = Vec2::new(41.5, 41.5);
-        let expected_cell = Cell::new(&new_pos, &grid)?;
-        entity.pos = new_pos;
+        let a = grid.create(Vec2::new(10.0, 10.0), Vec2::new(2.0, 2.0), ())?;
 
         // Act
-        let entity = grid.update(entity)?;
+        let created = grid.create(Vec2::new(20.0, 20.0), Vec2::new(-2.0, 2.0), ());
+        let resized = grid.set_size(a, Vec2::new(2.0, -0.5));
+        let flat = grid.create(Vec2::new(30.0, 30.0), Vec2::new(0.0, 0.0), ());
 
         // Assert
-        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
-        assert_eq!(occupied_cells, 1, "expected 1 cell being occopied");
-        let res = grid.cells.get(&expected_none_cell);
-        assert!(res.is_none());
-        let res = grid.cells.get(&expected_cell);
-        assert!(res.is_some());
-        let id = res.unwrap()[0];
-        assert_eq!(entity.id, id);
+        assert!(matches!(created, Err(Error::NegativeSize)));
+        assert!(matches!(resized, Err(Error::NegativeSize)));
+        assert_eq!(grid.get(a).unwrap().size(), &Vec2::new(2.0, 2.0));
+        assert_eq!(
+            grid.query_rect(Vec2::new(30.0, 30.0), Vec2::new(30.0, 30.0)),
+            vec![flat?]
+        );
         Ok(())
     }
 
-    // New tests for find_nearest
     #[test]
-    fn find_nearest_single_entity() -> anyhow::Result<(), Error> {
+    fn huge_entities_are_refused() -> anyhow::Result<(), Error> {
         // Arrange
-        let mut grid = create_grid();
-        let pos = Vec2::new(42.5, 42.5);
-        let size = Vec2::new(1.0, 1.0);
+        let mut grid = SpatialHash::unbounded(Vec2::new(1.0, 1.0))?;
+        let a = grid.create(Vec2::new(0.0, 0.0), Vec2::new(2.0, 2.0), ())?;
 
         // Act
-        let entity = grid.create(pos, size)?;
-        let found = grid.find_nearest(&entity)?;
+        let beyond_i32 = grid.create(Vec2::new(0.0, 0.0), Vec2::new(1e10, 1.0), ());
+        let billions = grid.create(Vec2::new(0.0, 0.0), Vec2::new(1e5, 1e5), ());
+        let grown = grid.set_size(a, Vec2::new(1e10, 1e10));
+        let largest = grid.create(Vec2::new(0.0, 0.0), Vec2::new(1023.0, 1023.0), ());
 
-        // Assert: should find itself only
-        assert_eq!(found.len(), 1);
-        assert!(found.contains(&entity.id));
+        // Assert
+        // cells past the i32 range do not exist, however few of them
+        assert!(matches!(beyond_i32, Err(Error::OutOfBounds)));
+        assert!(matches!(billions, Err(Error::FootprintTooLarge)));
+        assert!(matches!(grown, Err(Error::OutOfBounds)));
+        assert_eq!(grid.get(a).unwrap().size(), &Vec2::new(2.0, 2.0));
+        assert_eq!(
+            grid.record_at(largest?.index).footprint.len(),
+            MAX_FOOTPRINT_CELLS
+        );
         Ok(())
     }
 
     #[test]
-    fn find_nearest_includes_neighbor_when_large() -> anyhow::Result<(), Error> {
-        // Arrange
-        let mut grid = create_grid();
-        // create a larger entity that spans multiple cells
-        let pos_a = Vec2::new(42.5, 42.5);
-        let size_a = Vec2::new(3.0, 3.0); // spans approx cols 41..=43
-        let entity_a = grid.create(pos_a, size_a)?;
+    fn unbounded_grid_rejects_invalid_cell_size() {
+        assert!(matches!(
+            SpatialHash::<()>::unbounded(Vec2::new(0.0, 1.0)),
+            Err(Error::InvalidCellSize)
+        ));
+        assert!(SpatialHash::<()>::unbounded(Vec2::new(1.0, f32::NAN)).is_err());
+    }
 
-        // create a nearby entity inside that span
-        let pos_b = Vec2::new(43.5, 42.5);
-        let size_b = Vec2::new(1.0, 1.0);
-        let entity_b = grid.create(pos_b, size_b)?;
+    #[test]
+    fn integer_grid_maps_tiles_to_cells() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid: SpatialHash<(), HashMapStorage, i32> =
+            SpatialHash::new(Vec2::new(4, 4), Vec2::new(-8, -8), Vec2::new(23, 23))?;
+        let tile = Vec2::new(1, 1);
 
         // Act
-        let found = grid.find_nearest(&entity_a)?;
+        let a = grid.create(Vec2::new(-1, -1), tile.clone(), ())?;
+        let b = grid.create(Vec2::new(-5, 0), tile.clone(), ())?;
+        let c = grid.create(Vec2::new(10, 10), Vec2::new(3, 3), ())?;
+        let beyond = grid.create(Vec2::new(24, 0), tile, ());
 
-        // Assert: should find both ids (self and neighbor)
-        assert!(found.contains(&entity_a.id), "should contain self id");
-        assert!(found.contains(&entity_b.id), "should contain neighbor id");
-        assert_eq!(found.len(), 2);
+        // Assert
+        assert!(matches!(beyond, Err(Error::OutOfBounds)));
+        assert!(grid.cells.contains_key(&Cell { col: 1, row: 1 }));
+        assert!(grid.cells.contains_key(&Cell { col: 0, row: 2 }));
+        assert!(grid.cells.contains_key(&Cell { col: 4, row: 4 }));
+        assert_eq!(grid.cells.len(), 3);
+        assert_eq!(
+            grid.query_rect(Vec2::new(-1, -1), Vec2::new(-1, -1)),
+            vec![a]
+        );
+        let sorted = grid.query_radius_sorted(Vec2::new(0, 0), 6.0);
+        assert_eq!(sorted, vec![(a, 2f64.sqrt()), (b, 5.0)]);
+        assert_eq!(grid.k_nearest(Vec2::new(12, 12), 1)[0].0, c);
+        let hit = grid
+            .raycast(Vec2::new(0, 10), Vec2::new(1, 0), 100.0)
+            .unwrap();
+        assert_eq!((hit.handle, hit.distance), (c, 9.0));
+        assert_eq!(hit.normal, Vec2::new(-1.0, 0.0));
         Ok(())
     }
 
     #[test]
-    fn find_nearest_does_not_include_far_entities() -> anyhow::Result<(), Error> {
+    fn even_sized_integer_boxes_cover_exactly_their_size() -> anyhow::Result<(), Error> {
         // Arrange
-        let mut grid = create_grid();
-        let pos_a = Vec2::new(42.5, 42.5);
-        let size_a = Vec2::new(1.0, 1.0);
-        let entity_a = grid.create(pos_a, size_a)?;
-
-        // create a far away entity
-        let pos_far = Vec2::new(50.5, 50.5);
-        let size_far = Vec2::new(1.0, 1.0);
-        let entity_far = grid.create(pos_far, size_far)?;
+        let mut grid: SpatialHash<(), HashMapStorage, i32> =
+            SpatialHash::new(Vec2::new(1, 1), Vec2::new(0, 0), Vec2::new(99, 99))?;
 
         // Act
-        let found = grid.find_nearest(&entity_a)?;
+        let two = grid.create(Vec2::new(10, 10), Vec2::new(2, 2), ())?;
+        let three = grid.create(Vec2::new(50, 50), Vec2::new(3, 3), ())?;
 
-        // Assert: should only find the near entity (self)
-        assert!(found.contains(&entity_a.id));
-        assert!(!found.contains(&entity_far.id));
-        assert_eq!(found.len(), 1);
+        // Assert
+        let view = grid.get(two).unwrap();
+        assert_eq!(
+            (view.min(), view.max()),
+            (Vec2::new(9, 9), Vec2::new(10, 10))
+        );
+        assert_eq!(grid.record_at(two.index).footprint.len(), 4);
+        assert_eq!(grid.record_at(three.index).footprint.len(), 9);
+        assert_eq!(grid.query_rect(Vec2::new(9, 9), Vec2::new(9, 9)), vec![two]);
+        assert!(
+            grid.query_rect(Vec2::new(11, 9), Vec2::new(11, 11))
+                .is_empty()
+        );
+        assert!(
+            grid.query_rect(Vec2::new(9, 11), Vec2::new(11, 11))
+                .is_empty()
+        );
+        assert!(grid.query_rect(Vec2::new(51, 51), Vec2::new(51, 51)) == vec![three]);
         Ok(())
     }
 
-    // TODO change Entity to invalid position
-    // TODO update two Entities that are on the same spot at the beginning
     #[test]
-    fn create_entity_no_size() -> anyhow::Result<(), Error> {
+    fn integer_grids_refuse_boxes_past_the_integer_range() -> anyhow::Result<(), Error> {
         // Arrange
-        let mut grid = create_grid();
-        let pos = Vec2::new(42.0, 42.0);
-        let expected_cell = Cell::new(&pos, &grid)?;
-        dbg! {&expected_cell};
-        let size = Vec2::new(0.0, 0.0);
+        let mut unbounded: SpatialHash<(), HashMapStorage, i32> =
+            SpatialHash::unbounded(Vec2::new(1, 1))?;
+        let edge = unbounded.create(Vec2::new(i32::MAX - 2, 0), Vec2::new(4, 4), ())?;
 
         // Act
-        let res = grid.create(pos, size);
+        let past_max = unbounded.create(Vec2::new(i32::MAX, 0), Vec2::new(4, 4), ());
+        let past_min = unbounded.create(Vec2::new(0, i32::MIN), Vec2::new(4, 4), ());
+        unbounded.translate(edge, Vec2::new(1, 0))?;
+        let pushed = unbounded.translate(edge, Vec2::new(1, 0));
+        let bounded = SpatialHash::<(), HashMapStorage, i32>::new(
+            Vec2::new(4, 4),
+            Vec2::new(0, 0),
+            Vec2::new(i32::MAX, 10),
+        );
+        let padded = SpatialHash::<(), HashMapStorage, i32>::new(
+            Vec2::new(4, 4),
+            Vec2::new(0, 0),
+            Vec2::new(i32::MAX - 1, 10),
+        );
 
         // Assert
-        assert!(res.is_ok());
-        let entity = res.unwrap();
-        dbg! {&entity};
-        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
-        assert_eq!(occupied_cells, 1, "expected 1 cell being occopied");
-        let res = grid.cells.get(&expected_cell);
-        assert!(res.is_some());
-        let id = res.unwrap()[0];
-        assert_eq!(entity.id, id);
+        assert!(matches!(past_max, Err(Error::OutOfBounds)));
+        assert!(matches!(past_min, Err(Error::OutOfBounds)));
+        assert!(matches!(pushed, Err(Error::OutOfBounds)));
+        assert!(matches!(bounded, Err(Error::OutOfBounds)));
+        assert!(matches!(padded, Err(Error::OutOfBounds)));
+        assert_eq!(unbounded.get(edge).unwrap().max(), Vec2::new(i32::MAX, 1));
+        // twice the box does not fit anymore
+        assert!(matches!(
+            unbounded.find_nearest(edge),
+            Err(Error::OutOfBounds)
+        ));
         Ok(())
     }
 
     #[test]
-    fn create_two_entities() -> anyhow::Result<(), Error> {
-        // Arrange
-        let mut grid = create_grid();
-        let pos = Vec2::new(42.5, 42.5);
-        let pos2 = Vec2::new(30.5, 30.5);
-        let expected_cell = Cell::new(&pos, &grid)?;
-        let expected_cell2 = Cell::new(&pos2, &grid)?;
+    fn unbounded_grids_refuse_cells_past_i32() -> anyhow::Result<(), Error> {
+        // Arrange: 2147483520 is the last f32 below i32::MAX
+        let mut grid = SpatialHash::unbounded(Vec2::new(1.0, 1.0))?;
         let size = Vec2::new(1.0, 1.0);
+        let near = grid.create(Vec2::new(0.0, 0.0), size.clone(), ())?;
+        let edge = grid.create(Vec2::new(2_147_483_520.0, 0.0), size.clone(), ())?;
 
         // Act
-        let res = grid.create(pos, size.clone());
-        let res2 = grid.create(pos2, size);
+        let beyond = grid.create(Vec2::new(3e9, 0.0), size.clone(), ());
+        let below = grid.create(Vec2::new(0.0, -3e9), size.clone(), ());
+        let pushed = grid.set_position(near, Vec2::new(3e9, 0.0));
+        let nearest = grid.k_nearest(Vec2::new(3e9, 0.0), 2);
 
         // Assert
-        assert!(res.is_ok());
-        assert!(res2.is_ok());
-        let entity = res.unwrap();
-        let entity2 = res2.unwrap();
-        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
-        assert_eq!(occupied_cells, 2, "expected 2 cell being occopied");
-        let res = grid.cells.get(&expected_cell);
-        let res2 = grid.cells.get(&expected_cell2);
-        assert!(res.is_some());
-        assert!(res2.is_some());
-        let id = res.unwrap()[0];
-        let id2 = res2.unwrap()[0];
-        assert_eq!(entity.id, id);
-        assert_eq!(entity2.id, id2);
+        assert!(matches!(beyond, Err(Error::OutOfBounds)));
+        assert!(matches!(below, Err(Error::OutOfBounds)));
+        assert!(matches!(pushed, Err(Error::OutOfBounds)));
+        let order: Vec<_> = nearest.iter().map(|(handle, _)| *handle).collect();
+        assert_eq!(order, vec![edge, near]);
+        assert_eq!(grid.k_nearest(Vec2::new(2e9, 0.0), 1)[0].0, edge);
         Ok(())
     }
 
     #[test]
-    fn create_two_overlapping_entities() -> anyhow::Result<(), Error> {
+    fn k_nearest_searches_up_to_the_last_i32_cell() -> anyhow::Result<(), Error> {
+        // Arrange: the box covers the cells up to i32::MAX
+        let mut grid: SpatialHash<(), HashMapStorage, i32> =
+            SpatialHash::unbounded(Vec2::new(1, 1))?;
+        let edge = grid.create(Vec2::new(i32::MAX - 1, i32::MAX - 1), Vec2::new(3, 3), ())?;
+        let below = grid.create(Vec2::new(i32::MAX - 10, i32::MAX - 10), Vec2::new(1, 1), ())?;
+
+        // Act
+        let nearest = grid.k_nearest(Vec2::new(i32::MAX, i32::MAX), 2);
+
+        // Assert
+        let order: Vec<_> = nearest.iter().map(|(handle, _)| *handle).collect();
+        assert_eq!(order, vec![edge, below]);
+        assert_eq!(nearest[0].1, 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn f64_grid_stays_accurate_far_from_origin() -> anyhow::Result<(), Error> {
+        // Arrange: an f32 can not even tell these positions apart
+        let far = 1e9;
+        assert_eq!(far as f32 + 0.5, far as f32);
+        let mut grid: SpatialHash<(), HashMapStorage, f64> =
+            SpatialHash::unbounded(Vec2::new(1.0, 1.0))?;
+        let size = Vec2::new(0.5, 0.5);
+
+        // Act
+        let a = grid.create(Vec2::new(far + 0.5, far + 0.5), size.clone(), ())?;
+        let b = grid.create(Vec2::new(far + 1.5, far + 0.5), size, ())?;
+
+        // Assert
+        assert!(grid.cells.contains_key(&Cell {
+            col: 1_000_000_001,
+            row: 1_000_000_000
+        }));
+        assert_eq!(
+            grid.query_rect(
+                Vec2::new(far + 0.1, far + 0.1),
+                Vec2::new(far + 0.9, far + 0.9)
+            ),
+            vec![a]
+        );
+        assert_eq!(
+            grid.query_radius(Vec2::new(far + 0.9, far + 0.5), 0.3),
+            vec![a]
+        );
+        assert_eq!(grid.k_nearest(Vec2::new(far + 2.0, far + 0.5), 1)[0].0, b);
+        Ok(())
+    }
+
+    #[test]
+    fn every_storage_answers_the_same() -> anyhow::Result<(), Error> {
         // Arrange
-        let mut grid = create_grid();
-        let pos = Vec2::new(42.5, 42.5);
-        let pos2 = Vec2::new(42.5, 42.5);
-        let expected_cell = Cell::new(&pos, &grid)?;
-        let size = Vec2::new(1.0, 1.0);
+        let cell_size = Vec2::new(4.0, 4.0);
+        let (start, end) = (Vec2::new(-50.0, -50.0), Vec2::new(49.0, 49.0));
+        let mut sparse = SpatialHash::new(cell_size.clone(), start.clone(), end.clone())?;
+        let mut dense = SpatialHash::dense(cell_size.clone(), start.clone(), end.clone())?;
+        let mut sorted = SpatialHash::<_, BTreeStorage>::with_storage(cell_size, start, end)?;
 
         // Act
-        let res = grid.create(pos, size.clone());
-        let res2 = grid.create(pos2, size);
+        populate(&mut sparse)?;
+        populate(&mut dense)?;
+        populate(&mut sorted)?;
 
         // Assert
-        assert!(res.is_ok());
-        assert!(res2.is_ok());
-        let entity = res.unwrap();
-        let entity2 = res2.unwrap();
-        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
-        assert_eq!(occupied_cells, 2, "expected 2 cell being occopied");
-        let res = grid.cells.get(&expected_cell);
-        assert!(res.is_some());
-        let id = res.unwrap()[0];
-        let id2 = res.unwrap()[1];
-        assert_eq!(entity.id, id);
-        assert_eq!(entity2.id, id2);
+        let expected = answers(&sparse);
+        assert_eq!(answers(&dense), expected);
+        assert_eq!(answers(&sorted), expected);
+        let cells: Vec<_> = sorted.cells.iter().map(|(cell, _)| cell).collect();
+        assert!(cells.is_sorted(), "btree storage iterates in cell order");
         Ok(())
     }
 
     #[test]
-    fn create_bigger_entity() -> anyhow::Result<(), Error> {
+    fn bounded_grids_check_their_dimensions() {
+        let (start, end) = (Vec2::new(0.0, 0.0), Vec2::new(99.0, 99.0));
+        let zero = SpatialHash::<()>::new(Vec2::new(0.0, 1.0), start.clone(), end.clone());
+        let nan = SpatialHash::<()>::new(Vec2::new(1.0, f32::NAN), start.clone(), end);
+        let wide = SpatialHash::<()>::new(Vec2::new(1.0, 1.0), start, Vec2::new(3e9, 10.0));
+        assert!(matches!(zero, Err(Error::InvalidCellSize)));
+        assert!(matches!(nan, Err(Error::InvalidCellSize)));
+        assert!(matches!(wide, Err(Error::OutOfBounds)));
+    }
+
+    #[test]
+    fn dense_storage_needs_bounds() {
+        let res = SpatialHash::<(), DenseStorage>::unbounded_with_storage(Vec2::new(1.0, 1.0));
+        assert!(matches!(res, Err(Error::StorageNeedsBounds)));
+    }
+
+    #[test]
+    fn dense_storage_refuses_huge_grids() {
+        let (start, end) = (Vec2::new(0.0, 0.0), Vec2::new(99.0, 99.0));
+        let tiny = SpatialHash::<(), DenseStorage>::dense(
+            Vec2::new(1e-3, 1e-3),
+            start.clone(),
+            end.clone(),
+        );
+        let zero = SpatialHash::<(), DenseStorage>::dense(Vec2::new(0.0, 0.0), start, end);
+        assert!(matches!(tiny, Err(Error::TooManyCells)));
+        assert!(matches!(zero, Err(Error::InvalidCellSize)));
+    }
+
+    #[test]
+    fn dense_storage_walks_occupied_cells_only() -> anyhow::Result<(), Error> {
         // Arrange
-        let mut grid = create_grid();
-        let pos = Vec2::new(42.5, 42.5);
-        let expected_cell = Cell::new(&pos, &grid)?;
-        let size = Vec2::new(3.0, 3.0);
+        let mut grid = SpatialHash::dense(
+            Vec2::new(1.0, 1.0),
+            Vec2::new(0.0, 0.0),
+            Vec2::new(999.0, 999.0),
+        )?;
+        let size = Vec2::new(1.0, 1.0);
+        let a = grid.create(Vec2::new(10.5, 10.5), size.clone(), ())?;
+        let b = grid.create(Vec2::new(500.5, 20.5), size.clone(), ())?;
+        let c = grid.create(Vec2::new(900.5, 900.5), size, ())?;
 
         // Act
-        let res = grid.create(pos, size);
+        grid.remove(a)?;
+        let mut cells: Vec<_> = grid.cells.iter().map(|(cell, _)| cell).collect();
 
         // Assert
-        assert!(res.is_ok());
-        let entity = res.unwrap();
-        let occupied_cells: usize = grid.cells.values().map(|v| v.len()).sum();
-        // checks if every
-        assert!(grid
-            .cells
-            .values()
-            .all(|v| v.iter().all(|&id| id == entity.id)));
-        assert_eq!(occupied_cells, 9, "expected 3x3 cells being occopied");
-        let res = grid.cells.get(&expected_cell);
-        assert!(res.is_some());
-        let id = res.unwrap()[0];
-        assert_eq!(entity.id, id);
-        // check if every identity is the same
+        cells.sort();
+        assert_eq!(
+            cells,
+            vec![Cell { col: 500, row: 20 }, Cell { col: 900, row: 900 }]
+        );
+        assert_eq!(grid.k_nearest(Vec2::new(0.0, 0.0), 1)[0].0, b);
+        assert_eq!(
+            grid.query_rect(Vec2::new(0.0, 0.0), Vec2::new(999.0, 999.0))
+                .len(),
+            2
+        );
+        grid.remove(b)?;
+        assert_eq!(
+            grid.cells.iter().map(|(_, vec)| vec[0]).collect::<Vec<_>>(),
+            vec![c.index]
+        );
+        Ok(())
+    }
+
+    /// Creates, moves and removes a fixed set of entities.
+    fn populate<S: CellStorage>(grid: &mut SpatialHash<i32, S>) -> anyhow::Result<(), Error> {
+        let mut handles = Vec::new();
+        for i in 0..40 {
+            let pos = Vec2::new((i * 37 % 97) as f32 - 48.0, (i * 53 % 89) as f32 - 44.0);
+            let size = Vec2::new(1.0 + (i % 5) as f32, 1.0 + (i % 3) as f32);
+            handles.push(grid.create(pos, size, i)?);
+        }
+        for handle in handles.iter().step_by(3) {
+            grid.translate(*handle, Vec2::new(2.5, -1.5))?;
+        }
+        for handle in handles.iter().skip(1).step_by(4) {
+            grid.remove(*handle)?;
+        }
         Ok(())
     }
 
+    type Answers = (
+        Vec<EntityHandle>,
+        Vec<(EntityHandle, f32)>,
+        Vec<(EntityHandle, f32)>,
+        Vec<(EntityHandle, EntityHandle)>,
+        usize,
+    );
+
+    /// Results of every kind of query, sorted where the order is unspecified.
+    fn answers<S: CellStorage>(grid: &SpatialHash<i32, S>) -> Answers {
+        let mut rect = grid.query_rect(Vec2::new(-20.0, -30.0), Vec2::new(10.0, 5.0));
+        rect.sort_by_key(|handle| handle.index());
+        let center = Vec2::new(3.0, -7.0);
+        let radius = grid.query_radius_sorted(center.clone(), 25.0);
+        let nearest = grid.k_nearest(center, 5);
+        let mut pairs: Vec<_> = grid.collision_pairs().collect();
+        pairs.sort_by_key(|(a, b)| (a.index(), b.index()));
+        (rect, radius, nearest, pairs, grid.cells.len())
+    }
+
     fn create_grid() -> SpatialHash {
         let cell_size = Vec2::new(1.0, 1.0);
         let start = Vec2::new(0.0, 0.0);
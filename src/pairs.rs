@@ -0,0 +1,166 @@
+use crate::query::Seen;
+use crate::scalar::Scalar;
+use crate::storage::CellStorage;
+use crate::{Cell, EntityHandle, SpatialHash};
+
+impl<T, S: CellStorage, N: Scalar> SpatialHash<T, S, N> {
+    /// Every pair of entities sharing at least one cell, each unordered pair
+    /// exactly once.
+    pub fn collision_pairs(&self) -> impl Iterator<Item = (EntityHandle, EntityHandle)> + '_ {
+        self.candidate_pairs()
+            .map(|(a, b)| (self.handle_at(a), self.handle_at(b)))
+    }
+
+    /// Like [`SpatialHash::collision_pairs`], but only pairs whose bounding boxes
+    /// actually overlap.
+    pub fn overlapping_pairs(&self) -> impl Iterator<Item = (EntityHandle, EntityHandle)> + '_ {
+        self.candidate_pairs()
+            .filter(|&(a, b)| {
+                let other = self.view_at(b);
+                self.view_at(a).overlaps(&other.min(), &other.max())
+            })
+            .map(|(a, b)| (self.handle_at(a), self.handle_at(b)))
+    }
+
+    fn candidate_pairs(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let in_cells = self
+            .buckets()
+            .flat_map(move |(cell, vec)| self.cell_pairs(cell, vec));
+        in_cells.chain(self.overflow_pairs())
+    }
+
+    /// Pairs of entities in the bucket of `cell` that meet there first.
+    pub(crate) fn cell_pairs<'a>(
+        &'a self,
+        cell: Cell,
+        vec: &'a [u32],
+    ) -> impl Iterator<Item = (u32, u32)> + 'a {
+        (0..vec.len())
+            .flat_map(move |i| (i + 1..vec.len()).map(move |j| (vec[i], vec[j])))
+            // two entities can share several cells, only the first of them reports the pair
+            .filter(move |&(a, b)| self.first_shared_cell(a, b) == cell)
+    }
+
+    /// Pairs with entities that only live in the outside bucket and therefore
+    /// never meet anyone in a cell.
+    pub(crate) fn overflow_pairs(&self) -> Vec<(u32, u32)> {
+        let overflowed: Vec<u32> = self
+            .outside
+            .iter()
+            .copied()
+            .filter(|&index| self.record_at(index).footprint.cells.is_none())
+            .collect();
+        let mut pairs = Vec::new();
+        for (i, &a) in overflowed.iter().enumerate() {
+            pairs.extend(overflowed[i + 1..].iter().map(|&b| (a, b)));
+            let view = self.view_at(a);
+            let Some((start, end)) = self.clamped_cell_range(&view.min(), &view.max()) else {
+                continue;
+            };
+            let mut seen = self.visited(None);
+            for col in start.col..=end.col {
+                for row in start.row..=end.row {
+                    let Some(vec) = self.bucket(&Cell { col, row }) else {
+                        continue;
+                    };
+                    pairs.extend(
+                        vec.iter()
+                            .filter(|&&b| seen.first_visit(b))
+                            .map(|&b| (a, b)),
+                    );
+                }
+            }
+        }
+        pairs
+    }
+
+    fn first_shared_cell(&self, a: u32, b: u32) -> Cell {
+        let cells = |index| {
+            self.record_at(index)
+                .footprint
+                .cells
+                .expect("entities in cells have a cell range")
+        };
+        let ((a_start, _), (b_start, _)) = (cells(a), cells(b));
+        Cell {
+            col: a_start.col.max(b_start.col),
+            row: a_start.row.max(b_start.row),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::vec2::*;
+    use crate::{EntityHandle, SpatialHash};
+
+    #[test]
+    fn collision_pairs_reports_multi_cell_pairs_once() -> anyhow::Result<(), Error> {
+        // Arrange: both entities span the same 3x3 cells
+        let mut grid = create_grid();
+        let a = grid.create(Vec2::new(42.5, 42.5), Vec2::new(3.0, 3.0), ())?;
+        let b = grid.create(Vec2::new(42.5, 42.5), Vec2::new(3.0, 3.0), ())?;
+        let c = grid.create(Vec2::new(44.5, 42.5), Vec2::new(1.0, 1.0), ())?;
+        grid.create(Vec2::new(80.5, 80.5), Vec2::new(1.0, 1.0), ())?;
+
+        // Act
+        let pairs = sorted(grid.collision_pairs());
+
+        // Assert: c touches neither box's cells, so only a-b is a candidate
+        assert_eq!(pairs, vec![(a, b)]);
+        let d = grid.create(Vec2::new(43.5, 43.5), Vec2::new(1.0, 1.0), ())?;
+        assert_eq!(sorted(grid.collision_pairs()), vec![(a, b), (a, d), (b, d)]);
+        assert!(
+            !sorted(grid.collision_pairs())
+                .iter()
+                .any(|&(x, y)| x == c || y == c)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn overlapping_pairs_drops_pairs_that_only_share_a_cell() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = SpatialHash::new(
+            Vec2::new(10.0, 10.0),
+            Vec2::new(0.0, 0.0),
+            Vec2::new(99.0, 99.0),
+        )?;
+        let a = grid.create(Vec2::new(2.0, 2.0), Vec2::new(2.0, 2.0), ())?;
+        let b = grid.create(Vec2::new(3.0, 3.0), Vec2::new(2.0, 2.0), ())?;
+        let c = grid.create(Vec2::new(8.0, 8.0), Vec2::new(1.0, 1.0), ())?;
+
+        // Act
+        let candidates = sorted(grid.collision_pairs());
+        let overlapping = sorted(grid.overlapping_pairs());
+
+        // Assert
+        assert_eq!(candidates, vec![(a, b), (a, c), (b, c)]);
+        assert_eq!(overlapping, vec![(a, b)]);
+        Ok(())
+    }
+
+    fn sorted(
+        pairs: impl Iterator<Item = (EntityHandle, EntityHandle)>,
+    ) -> Vec<(EntityHandle, EntityHandle)> {
+        let mut pairs: Vec<_> = pairs
+            .map(|(x, y)| {
+                if x.index() < y.index() {
+                    (x, y)
+                } else {
+                    (y, x)
+                }
+            })
+            .collect();
+        pairs.sort_by_key(|(x, y)| (x.index(), y.index()));
+        pairs
+    }
+
+    fn create_grid() -> SpatialHash {
+        let cell_size = Vec2::new(1.0, 1.0);
+        let start = Vec2::new(0.0, 0.0);
+        let end = Vec2::new(99.0, 99.0);
+        SpatialHash::new(cell_size, start, end).unwrap()
+    }
+}
@@ -0,0 +1,270 @@
+use crate::error::Error;
+use crate::query::QueryScratch;
+use crate::rebuild::{SortedCells, apply_move, footprint_entries};
+use crate::scalar::Scalar;
+use crate::storage::CellStorage;
+use crate::vec2::*;
+use crate::{Cell, EntityHandle, SpatialHash};
+use rayon::prelude::*;
+
+impl<T: Sync, S: CellStorage + Sync, N: Scalar> SpatialHash<T, S, N> {
+    /// Runs [`SpatialHash::query_radius`] for every `(center, radius)` on the
+    /// rayon thread pool. The answers come back in the order of `queries`,
+    /// each exactly as the sequential query would have given it.
+    pub fn par_query_radius_batch(
+        &self,
+        queries: &[(Vec2<N>, N::Float)],
+    ) -> Vec<Vec<EntityHandle>> {
+        queries
+            .par_iter()
+            .map_init(QueryScratch::new, |scratch, (center, radius)| {
+                let mut found = Vec::new();
+                self.query_radius_into(center.clone(), *radius, scratch, &mut found);
+                found
+            })
+            .collect()
+    }
+
+    /// [`SpatialHash::collision_pairs`] with the cells spread over the rayon
+    /// thread pool, in the same order as the sequential version.
+    pub fn par_collision_pairs(&self) -> Vec<(EntityHandle, EntityHandle)> {
+        let buckets: Vec<_> = self.buckets().collect();
+        let mut pairs: Vec<_> = buckets
+            .par_iter()
+            .flat_map_iter(|&(cell, vec)| self.cell_pairs(cell, vec))
+            .map(|(a, b)| (self.handle_at(a), self.handle_at(b)))
+            .collect();
+        pairs.extend(
+            self.overflow_pairs()
+                .into_iter()
+                .map(|(a, b)| (self.handle_at(a), self.handle_at(b))),
+        );
+        pairs
+    }
+
+    /// [`SpatialHash::rebuild_from`] spread over the rayon thread pool. The
+    /// footprints are worked out and applied in parallel, the cell entries of
+    /// all entities are sorted in parallel, then every band of cells is laid
+    /// out on its own and the bands are joined in order. The result is exactly
+    /// the layout of the sequential rebuild. If any entity is rejected, nothing
+    /// is changed and the error is the one the sequential rebuild reports.
+    pub fn par_rebuild_from(
+        &mut self,
+        entities: &[(EntityHandle, Vec2<N>, Vec2<N>)],
+    ) -> anyhow::Result<(), Error>
+    where
+        T: Send,
+    {
+        let moves: Vec<_> = entities
+            .par_iter()
+            .map(|(id, pos, size)| self.checked_move(*id, pos.clone(), size.clone()))
+            .collect();
+        // the first rejected entity in input order wins, as it would sequentially
+        let mut moves = moves
+            .into_iter()
+            .collect::<anyhow::Result<Vec<_>, Error>>()?;
+        // stable, so the last move of an entity listed twice wins, as it would sequentially
+        moves.par_sort_by_key(|(index, ..)| *index);
+        self.slots
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(index, slot)| {
+                let last = moves.partition_point(|(moved, ..)| *moved as usize <= index);
+                if let (Some(record), Some((moved, pos, size, footprint))) = (
+                    slot.entity.as_mut(),
+                    last.checked_sub(1).map(|last| &moves[last]),
+                ) && *moved as usize == index
+                {
+                    apply_move(record, pos.clone(), size.clone(), *footprint);
+                }
+            });
+
+        self.clear_layout();
+        let outside: Vec<u32> = self
+            .slots
+            .par_iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.entity.as_ref().is_some_and(|e| e.footprint.outside))
+            .map(|(index, _)| index as u32)
+            .collect();
+        outside.iter().for_each(|&index| self.push_outside(index));
+        let used = self
+            .slots
+            .par_iter()
+            .filter_map(|slot| slot.entity.as_ref()?.footprint.cells)
+            .reduce_with(|(a_start, a_end), (b_start, b_end)| {
+                let start = Cell {
+                    col: a_start.col.min(b_start.col),
+                    row: a_start.row.min(b_start.row),
+                };
+                let end = Cell {
+                    col: a_end.col.max(b_end.col),
+                    row: a_end.row.max(b_end.row),
+                };
+                (start, end)
+            });
+        if let Some((start, end)) = used {
+            self.grow_extent(&start, &end);
+        }
+
+        let mut entries: Vec<(Cell, u32)> = self
+            .slots
+            .par_iter()
+            .enumerate()
+            .flat_map_iter(|(index, slot)| {
+                slot.entity
+                    .as_ref()
+                    .map(|record| footprint_entries(&record.footprint, index as u32))
+                    .into_iter()
+                    .flatten()
+            })
+            .collect();
+        // stable, so every cell lists its entities in slot order like the sequential rebuild
+        entries.par_sort_by_key(|(cell, _)| (cell.row, cell.col));
+
+        // cut the entries into one band per thread, never through a cell
+        let band_len = entries.len().div_ceil(rayon::current_num_threads().max(1));
+        let mut bands = Vec::new();
+        let mut rest = entries.as_slice();
+        while !rest.is_empty() {
+            let mut at = band_len.clamp(1, rest.len());
+            while at < rest.len() && rest[at].0 == rest[at - 1].0 {
+                at += 1;
+            }
+            let (band, tail) = rest.split_at(at);
+            bands.push(band);
+            rest = tail;
+        }
+        let parts: Vec<SortedCells> = bands
+            .par_iter()
+            .map(|band| {
+                let mut part = SortedCells::default();
+                part.fill_grouped(band);
+                part
+            })
+            .collect();
+
+        let mut sorted = self.frozen.take().unwrap_or_default();
+        sorted.clear();
+        parts.into_iter().for_each(|part| sorted.append(part));
+        self.frozen = Some(sorted);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BoundsPolicy;
+    use crate::error::Error;
+
+    #[test]
+    fn par_queries_match_sequential_ones() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid().with_bounds_policy(BoundsPolicy::Overflow);
+        for i in 0..500 {
+            let pos = Vec2::new((i * 37 % 101) as f32, (i * 53 % 103) as f32);
+            let size = Vec2::new(1.0 + (i % 4) as f32, 1.0 + (i % 3) as f32);
+            grid.create(pos, size, ())?;
+        }
+        let queries: Vec<_> = (0..200)
+            .map(|i| {
+                (
+                    Vec2::new((i * 7 % 100) as f32, (i * 13 % 100) as f32),
+                    2.0 + (i % 9) as f32,
+                )
+            })
+            .collect();
+
+        // Act
+        let batch = grid.par_query_radius_batch(&queries);
+        let pairs = grid.par_collision_pairs();
+
+        // Assert
+        let sequential: Vec<_> = queries
+            .iter()
+            .map(|(center, radius)| grid.query_radius(center.clone(), *radius))
+            .collect();
+        assert_eq!(batch, sequential);
+        assert_eq!(pairs, grid.collision_pairs().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn par_rebuild_matches_sequential_rebuild() -> anyhow::Result<(), Error> {
+        // bounded grids take the counting sort path sequentially, unbounded ones the plain sort
+        let bounded = || create_grid().with_bounds_policy(BoundsPolicy::Overflow);
+        compare_rebuilds(bounded(), bounded())?;
+        let unbounded = || SpatialHash::unbounded(Vec2::new(4.0, 4.0)).unwrap();
+        compare_rebuilds(unbounded(), unbounded())?;
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_par_rebuild_changes_nothing() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(2.0, 2.0);
+        let a = grid.create(Vec2::new(10.0, 10.0), size.clone(), ())?;
+        let b = grid.create(Vec2::new(50.0, 50.0), size.clone(), ())?;
+        grid.remove(b)?;
+
+        // Act
+        let res = grid.par_rebuild_from(&[
+            (a, Vec2::new(300.0, 30.0), size.clone()),
+            (b, Vec2::new(30.0, 30.0), size),
+        ]);
+
+        // Assert
+        assert!(matches!(res, Err(Error::OutOfBounds)));
+        assert!(grid.frozen.is_none());
+        assert_eq!(grid.get(a).unwrap().pos(), &Vec2::new(10.0, 10.0));
+        Ok(())
+    }
+
+    fn compare_rebuilds(
+        mut sequential: SpatialHash,
+        mut parallel: SpatialHash,
+    ) -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut moves = Vec::new();
+        for i in 0..800 {
+            let pos = Vec2::new((i * 37 % 101) as f32, (i * 53 % 103) as f32);
+            let size = Vec2::new(1.0 + (i % 4) as f32, 1.0 + (i % 3) as f32);
+            let id = sequential.create(pos.clone(), size.clone(), ())?;
+            assert_eq!(parallel.create(pos, size.clone(), ())?, id);
+            if i % 3 != 0 {
+                let to = Vec2::new((i * 11 % 97) as f32 - 2.0, (i * 29 % 89) as f32);
+                moves.push((id, to, size));
+            }
+        }
+        // entities listed twice end up where their last move takes them
+        let again: Vec<_> = moves
+            .iter()
+            .step_by(7)
+            .map(|(id, to, size)| (*id, Vec2::new(to.y, to.x), size.clone()))
+            .collect();
+        moves.extend(again);
+
+        // Act
+        sequential.rebuild_from(moves.iter().cloned())?;
+        parallel.par_rebuild_from(&moves)?;
+
+        // Assert
+        assert_eq!(parallel.frozen, sequential.frozen);
+        assert_eq!(parallel.outside, sequential.outside);
+        assert_eq!(parallel.extent, sequential.extent);
+        assert_eq!(
+            parallel.collision_pairs().collect::<Vec<_>>(),
+            sequential.collision_pairs().collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    fn create_grid() -> SpatialHash {
+        let cell_size = Vec2::new(4.0, 4.0);
+        let start = Vec2::new(0.0, 0.0);
+        let end = Vec2::new(99.0, 99.0);
+        SpatialHash::new(cell_size, start, end).unwrap()
+    }
+}
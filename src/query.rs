@@ -0,0 +1,502 @@
+use crate::error::Error;
+use crate::scalar::Scalar;
+use crate::storage::CellStorage;
+use crate::vec2::*;
+use crate::{Cell, EntityHandle, EntityRecord, EntityView, Slot, SpatialHash};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+thread_local! {
+    // fallback of queries that bring no scratch of their own, see `Visited`
+    static LOCAL_SCRATCH: RefCell<QueryScratch> = RefCell::default();
+}
+
+/// Remembers which slot indices a query has already looked at, as entities
+/// spanning several cells show up once per cell.
+pub(crate) trait Seen {
+    /// Returns true the first time `index` shows up.
+    fn first_visit(&mut self, index: u32) -> bool;
+}
+
+/// Epoch of the query currently stamping the entity records, if any. Atomic so
+/// that queries can run from several threads at once; only the one holding the
+/// lease touches the stamps.
+#[derive(Debug, Default)]
+pub(crate) struct QueryStamps {
+    epoch: AtomicU32,
+    leased: AtomicBool,
+}
+
+impl Clone for QueryStamps {
+    /// A copy starts without any query running, see the stamps of cloned records.
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// Deduplication of one running query.
+///
+/// A query stamps every entity record it comes across with a fresh epoch, so
+/// a repeated id costs one comparison. Only one query can own the stamps at a
+/// time; one started while another is still running (say, next to a lazy
+/// iterator or on another thread) falls back to the caller's [`QueryScratch`],
+/// or else to one kept per thread, so it does not allocate either once the
+/// scratch has grown.
+pub(crate) enum Visited<'a, T, N> {
+    Stamps {
+        slots: &'a [Slot<EntityRecord<T, N>>],
+        stamps: &'a QueryStamps,
+        epoch: u32,
+    },
+    Scratch(&'a mut QueryScratch),
+    // taken out of `LOCAL_SCRATCH` and put back on drop
+    Local(QueryScratch),
+}
+
+impl<T, N> Seen for Visited<'_, T, N> {
+    fn first_visit(&mut self, index: u32) -> bool {
+        match self {
+            Visited::Stamps { slots, epoch, .. } => {
+                let record = slots[index as usize]
+                    .entity
+                    .as_ref()
+                    .expect("queries only come across live entities");
+                record.visited.swap(*epoch, Ordering::Relaxed) != *epoch
+            }
+            Visited::Scratch(scratch) => scratch.first_visit(index),
+            Visited::Local(scratch) => scratch.first_visit(index),
+        }
+    }
+}
+
+impl<T, N> Drop for Visited<'_, T, N> {
+    fn drop(&mut self) {
+        match self {
+            Visited::Stamps { stamps, .. } => stamps.leased.store(false, Ordering::Release),
+            Visited::Local(scratch) => put_back_local(std::mem::take(scratch)),
+            Visited::Scratch(_) => {}
+        }
+    }
+}
+
+/// Reusable memory for the `*_into` queries when they cannot stamp the entity
+/// records because another query is still running. Once it has grown to the
+/// number of entity slots in the grid, they still run without allocating.
+///
+/// Instead of clearing a set between queries, every query gets a new epoch
+/// and marks the slots it has seen with it.
+#[derive(Debug, Default)]
+pub struct QueryScratch {
+    // the epoch of the last query that saw each slot
+    stamps: Vec<u32>,
+    epoch: u32,
+}
+
+impl QueryScratch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a new query over `slots` slots, forgetting what earlier ones saw.
+    pub(crate) fn begin(&mut self, slots: usize) -> &mut Self {
+        self.cover(slots);
+        self.epoch = self.epoch.wrapping_add(1);
+        if self.epoch == 0 {
+            // stamps of 4 billion queries ago would look current again
+            self.stamps.fill(0);
+            self.epoch = 1;
+        }
+        self
+    }
+
+    /// Makes room for `slots` slots, for grids that grow while the query runs.
+    pub(crate) fn cover(&mut self, slots: usize) {
+        if self.stamps.len() < slots {
+            self.stamps.resize(slots, 0);
+        }
+    }
+}
+
+/// Runs `f` with the scratch of this thread, the one queries without a scratch
+/// of their own fall back to.
+pub(crate) fn with_local_scratch<R>(f: impl FnOnce(&mut QueryScratch) -> R) -> R {
+    let mut scratch = take_local();
+    let result = f(&mut scratch);
+    put_back_local(scratch);
+    result
+}
+
+fn take_local() -> QueryScratch {
+    LOCAL_SCRATCH
+        .try_with(|local| std::mem::take(&mut *local.borrow_mut()))
+        .unwrap_or_default()
+}
+
+fn put_back_local(scratch: QueryScratch) {
+    // a nested query may have put back a smaller one meanwhile
+    let _ = LOCAL_SCRATCH.try_with(|local| {
+        let mut local = local.borrow_mut();
+        if local.stamps.len() <= scratch.stamps.len() {
+            *local = scratch;
+        }
+    });
+}
+
+impl Seen for QueryScratch {
+    fn first_visit(&mut self, index: u32) -> bool {
+        let stamp = &mut self.stamps[index as usize];
+        if *stamp == self.epoch {
+            return false;
+        }
+        *stamp = self.epoch;
+        true
+    }
+}
+
+impl<T, S: CellStorage, N: Scalar> SpatialHash<T, S, N> {
+    /// Starts deduplicating a query, on the entity records if no other query
+    /// holds them, otherwise in `scratch` or the scratch of the thread.
+    pub(crate) fn visited<'a>(
+        &'a self,
+        scratch: Option<&'a mut QueryScratch>,
+    ) -> Visited<'a, T, N> {
+        let stamps = &self.query_stamps;
+        // only try to take the lease if it looks free, so threads querying
+        // side by side do not keep writing to the same cache line
+        if stamps.leased.load(Ordering::Relaxed) || stamps.leased.swap(true, Ordering::Acquire) {
+            let slots = self.slots.len();
+            return match scratch {
+                Some(scratch) => Visited::Scratch(scratch.begin(slots)),
+                None => {
+                    let mut local = take_local();
+                    local.begin(slots);
+                    Visited::Local(local)
+                }
+            };
+        }
+        let mut epoch = stamps.epoch.load(Ordering::Relaxed).wrapping_add(1);
+        if epoch == 0 {
+            // stamps of 4 billion queries ago would look current again
+            for slot in &self.slots {
+                if let Some(record) = &slot.entity {
+                    record.visited.store(0, Ordering::Relaxed);
+                }
+            }
+            epoch = 1;
+        }
+        stamps.epoch.store(epoch, Ordering::Relaxed);
+        Visited::Stamps {
+            slots: &self.slots,
+            stamps,
+            epoch,
+        }
+    }
+
+    /// Like [`SpatialHash::find_nearest`], but writes the entities into `out`
+    /// (cleared first). `scratch` is only used while another query is running.
+    pub fn find_nearest_into(
+        &self,
+        id: EntityHandle,
+        scratch: &mut QueryScratch,
+        out: &mut Vec<EntityHandle>,
+    ) -> anyhow::Result<(), Error> {
+        out.clear();
+        self.nearest_hits(id, &mut self.visited(Some(scratch)), |handle| {
+            out.push(handle)
+        })
+    }
+
+    /// Like [`SpatialHash::query_rect`], but writes the entities into `out`
+    /// (cleared first). `scratch` is only used while another query is running.
+    pub fn query_rect_into(
+        &self,
+        min: Vec2<N>,
+        max: Vec2<N>,
+        scratch: &mut QueryScratch,
+        out: &mut Vec<EntityHandle>,
+    ) {
+        out.clear();
+        self.rect_hits(&min, &max, &mut self.visited(Some(scratch)), |handle| {
+            out.push(handle)
+        });
+    }
+
+    /// Like [`SpatialHash::query_radius`], but writes the entities into `out`
+    /// (cleared first). `scratch` is only used while another query is running.
+    pub fn query_radius_into(
+        &self,
+        center: Vec2<N>,
+        radius: N::Float,
+        scratch: &mut QueryScratch,
+        out: &mut Vec<EntityHandle>,
+    ) {
+        out.clear();
+        self.radius_hits(
+            &center,
+            radius,
+            &mut self.visited(Some(scratch)),
+            |handle, _| out.push(handle),
+        );
+    }
+
+    /// Like [`SpatialHash::k_nearest_within`], but writes the entities and
+    /// their distances into `out` (cleared first), closest first. `scratch` is
+    /// only used while another query is running.
+    pub fn k_nearest_into(
+        &self,
+        point: Vec2<N>,
+        k: usize,
+        max_dist: N::Float,
+        scratch: &mut QueryScratch,
+        out: &mut Vec<(EntityHandle, N::Float)>,
+    ) {
+        out.clear();
+        self.nearest_k(&point, k, max_dist, &mut self.visited(Some(scratch)), out);
+    }
+
+    /// Lazy [`SpatialHash::query_rect`]: yields every entity once while walking
+    /// the cells, so `any`, `find` or `take` stop as soon as they have enough.
+    pub fn query_rect_iter(
+        &self,
+        min: Vec2<N>,
+        max: Vec2<N>,
+    ) -> impl Iterator<Item = EntityHandle> + '_ {
+        let cells = self.clamped_cell_range(&min, &max);
+        self.lazy_hits(cells, move |view| view.overlaps(&min, &max))
+    }
+
+    /// Lazy [`SpatialHash::query_radius`], see [`SpatialHash::query_rect_iter`].
+    pub fn query_radius_iter(
+        &self,
+        center: Vec2<N>,
+        radius: N::Float,
+    ) -> impl Iterator<Item = EntityHandle> + '_ {
+        let cells = self.radius_cell_range(&center, radius);
+        self.lazy_hits(cells, move |view| view.distance_to(&center) <= radius)
+    }
+
+    /// Everything in the outside bucket and the `cells` range that passes `hit`,
+    /// each entity once.
+    fn lazy_hits(
+        &self,
+        cells: Option<(Cell, Cell)>,
+        hit: impl Fn(&EntityView<N>) -> bool + 'static,
+    ) -> impl Iterator<Item = EntityHandle> + '_ {
+        let mut seen = self.visited(None);
+        let in_cells = cells
+            .into_iter()
+            .flat_map(|(start, end)| self.buckets_in(start, end))
+            .flatten();
+        self.outside
+            .iter()
+            .chain(in_cells)
+            .filter(move |&&index| seen.first_visit(index))
+            .map(|&index| self.view_at(index))
+            .filter(move |view| hit(view))
+            .map(|view| view.handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn into_queries_match_allocating_ones() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(6.0, 6.0);
+        let a = grid.create(Vec2::new(10.0, 10.0), size.clone(), ())?;
+        grid.create(Vec2::new(14.0, 12.0), size.clone(), ())?;
+        grid.create(Vec2::new(40.0, 40.0), size.clone(), ())?;
+        grid.create(Vec2::new(90.0, 10.0), size, ())?;
+        let mut scratch = QueryScratch::new();
+        let mut found = vec![a, a, a];
+        let mut nearest = Vec::new();
+
+        // Act & Assert
+        grid.query_rect_into(
+            Vec2::new(0.0, 0.0),
+            Vec2::new(20.0, 20.0),
+            &mut scratch,
+            &mut found,
+        );
+        assert_eq!(
+            sorted(found.clone()),
+            sorted(grid.query_rect(Vec2::new(0.0, 0.0), Vec2::new(20.0, 20.0)))
+        );
+        grid.query_radius_into(Vec2::new(12.0, 12.0), 30.0, &mut scratch, &mut found);
+        assert_eq!(
+            sorted(found.clone()),
+            sorted(grid.query_radius(Vec2::new(12.0, 12.0), 30.0))
+        );
+        grid.find_nearest_into(a, &mut scratch, &mut found)?;
+        assert_eq!(
+            sorted(found.clone()),
+            sorted(grid.find_nearest(a)?.into_iter().collect())
+        );
+        grid.k_nearest_into(
+            Vec2::new(0.0, 0.0),
+            3,
+            f32::INFINITY,
+            &mut scratch,
+            &mut nearest,
+        );
+        assert_eq!(nearest, grid.k_nearest(Vec2::new(0.0, 0.0), 3));
+        Ok(())
+    }
+
+    #[test]
+    fn stamps_forget_between_queries_and_epochs() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let a = grid.create(Vec2::new(10.0, 10.0), Vec2::new(6.0, 6.0), ())?;
+        assert_eq!(grid.query_radius(Vec2::new(10.0, 10.0), 1.0), vec![a]);
+        grid.query_stamps.epoch.store(u32::MAX, Ordering::Relaxed);
+
+        // Act
+        let wrapped = grid.query_radius(Vec2::new(10.0, 10.0), 1.0);
+        let found = grid.query_radius(Vec2::new(10.0, 10.0), 1.0);
+
+        // Assert
+        assert_eq!(wrapped, vec![a]);
+        assert_eq!(found, vec![a]);
+        assert_eq!(grid.query_stamps.epoch.load(Ordering::Relaxed), 2);
+        assert!(!grid.query_stamps.leased.load(Ordering::Relaxed));
+        Ok(())
+    }
+
+    #[test]
+    fn nested_queries_fall_back_to_the_scratch() -> anyhow::Result<(), Error> {
+        // Arrange: every entity spans four cells
+        let mut grid = create_grid();
+        let size = Vec2::new(2.0, 2.0);
+        let a = grid.create(Vec2::new(8.0, 8.0), size.clone(), ())?;
+        let b = grid.create(Vec2::new(8.5, 8.5), size, ())?;
+        let mut scratch = QueryScratch::new();
+        let mut found = Vec::new();
+
+        // Act
+        let outer: Vec<_> = grid
+            .query_rect_iter(Vec2::new(0.0, 0.0), Vec2::new(20.0, 20.0))
+            .map(|handle| {
+                grid.query_radius_into(Vec2::new(8.0, 8.0), 3.0, &mut scratch, &mut found);
+                (handle, sorted(found.clone()))
+            })
+            .collect();
+
+        // Assert
+        assert_eq!(
+            sorted(outer.iter().map(|(handle, _)| *handle).collect()),
+            vec![a, b]
+        );
+        assert!(outer.iter().all(|(_, inner)| inner == &vec![a, b]));
+        assert_eq!(scratch.epoch, 2, "both inner queries ran on the scratch");
+        assert_eq!(
+            grid.query_rect(Vec2::new(0.0, 0.0), Vec2::new(20.0, 20.0))
+                .len(),
+            2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn scratch_forgets_between_epochs() {
+        // Arrange
+        let mut scratch = QueryScratch::new();
+        scratch.begin(4);
+        assert!(scratch.first_visit(3));
+        scratch.epoch = u32::MAX;
+
+        // Act
+        scratch.begin(4);
+        let first = scratch.first_visit(3);
+        let again = scratch.first_visit(3);
+
+        // Assert
+        assert!(first && !again);
+        assert_eq!(scratch.epoch, 1);
+    }
+
+    #[test]
+    fn iter_queries_yield_each_entity_once() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid().with_bounds_policy(crate::BoundsPolicy::Overflow);
+        let size = Vec2::new(6.0, 6.0);
+        grid.create(Vec2::new(10.0, 10.0), size.clone(), ())?;
+        grid.create(Vec2::new(14.0, 12.0), size.clone(), ())?;
+        grid.create(Vec2::new(40.0, 40.0), size.clone(), ())?;
+        grid.create(Vec2::new(98.0, 50.0), size, ())?;
+
+        // Act
+        let rect: Vec<_> = grid
+            .query_rect_iter(Vec2::new(0.0, 0.0), Vec2::new(99.0, 99.0))
+            .collect();
+        let radius: Vec<_> = grid.query_radius_iter(Vec2::new(12.0, 12.0), 5.0).collect();
+
+        // Assert
+        assert_eq!(
+            sorted(rect),
+            sorted(grid.query_rect(Vec2::new(0.0, 0.0), Vec2::new(99.0, 99.0)))
+        );
+        assert_eq!(
+            sorted(radius),
+            sorted(grid.query_radius(Vec2::new(12.0, 12.0), 5.0))
+        );
+        assert!(
+            grid.query_rect_iter(Vec2::new(60.0, 0.0), Vec2::new(80.0, 30.0))
+                .next()
+                .is_none()
+        );
+        assert!(
+            grid.query_radius_iter(Vec2::new(40.0, 40.0), 1.0)
+                .any(|_| true)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn threads_share_the_grid_for_queries() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(6.0, 6.0);
+        let handles = (0..50)
+            .map(|i| grid.create(Vec2::new(1.5 * i as f32 + 5.0, 50.0), size.clone(), ()))
+            .collect::<anyhow::Result<Vec<_>, Error>>()?;
+        let expected = sorted(grid.query_rect(Vec2::new(0.0, 45.0), Vec2::new(99.0, 55.0)));
+
+        // Act
+        let answers: Vec<_> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..100)
+                            .map(|_| grid.query_rect(Vec2::new(0.0, 45.0), Vec2::new(99.0, 55.0)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        });
+
+        // Assert
+        assert_eq!(expected, sorted(handles));
+        assert!(answers.into_iter().all(|found| sorted(found) == expected));
+        Ok(())
+    }
+
+    fn sorted(mut handles: Vec<EntityHandle>) -> Vec<EntityHandle> {
+        handles.sort_by_key(|handle| handle.index());
+        handles
+    }
+
+    fn create_grid() -> SpatialHash {
+        let cell_size = Vec2::new(4.0, 4.0);
+        let start = Vec2::new(0.0, 0.0);
+        let end = Vec2::new(99.0, 99.0);
+        SpatialHash::new(cell_size, start, end).unwrap()
+    }
+}
@@ -0,0 +1,432 @@
+use crate::query::{Seen, Visited};
+use crate::scalar::{Float, Scalar};
+use crate::storage::{CellStorage, HashMapStorage};
+use crate::vec2::*;
+use crate::{Cell, EntityHandle, SpatialHash};
+
+/// An entity hit by a ray.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RayHit<F = f32> {
+    pub handle: EntityHandle,
+    /// Distance along the ray to the point where it enters the bounding box.
+    pub distance: F,
+    /// Normal of the box side that was hit, zero if the ray starts inside the box.
+    pub normal: Vec2<F>,
+}
+
+/// Walks the cells along a ray (Amanatides & Woo) and yields every hit in order
+/// of distance. The walk happens in the float type of `N`, see [`Scalar::Float`].
+pub struct RaycastIter<'a, T, S = HashMapStorage, N: Scalar = f32> {
+    spatial_hash: &'a SpatialHash<T, S, N>,
+    origin: Vec2<N::Float>,
+    dir: Vec2<N::Float>,
+    max_dist: N::Float,
+    cell: Cell,
+    step_col: i32,
+    step_row: i32,
+    t_max: Vec2<N::Float>,
+    t_delta: Vec2<N::Float>,
+    // hits up to this distance can no longer be beaten by a cell we have not visited
+    settled: N::Float,
+    done: bool,
+    seen: Visited<'a, T, N>,
+    // sorted farthest first, so the next hit sits at the end
+    pending: Vec<RayHit<N::Float>>,
+}
+
+impl<T, S: CellStorage, N: Scalar> SpatialHash<T, S, N> {
+    /// Returns the first entity hit by the ray from `origin` along `dir`, at most
+    /// `max_dist` away.
+    pub fn raycast(
+        &self,
+        origin: Vec2<N>,
+        dir: Vec2<N>,
+        max_dist: N::Float,
+    ) -> Option<RayHit<N::Float>> {
+        self.raycast_all(origin, dir, max_dist).next()
+    }
+
+    /// Returns every entity hit by the ray, closest first. Only the direction
+    /// of `dir` counts, not its length; a zero or non-finite `dir` hits nothing.
+    pub fn raycast_all(
+        &self,
+        origin: Vec2<N>,
+        dir: Vec2<N>,
+        max_dist: N::Float,
+    ) -> RaycastIter<'_, T, S, N> {
+        // scaled down to its largest component first, so squaring it can neither
+        // overflow nor underflow
+        let dir = dir.to_float();
+        let largest = dir.x.abs().max(dir.y.abs());
+        let walks = largest > N::Float::ZERO && largest.is_finite();
+        let dir = if walks {
+            let dir = dir.div(largest);
+            dir.div(dir.length())
+        } else {
+            dir
+        };
+        let mut iter = RaycastIter {
+            spatial_hash: self,
+            origin: origin.to_float(),
+            dir,
+            max_dist,
+            cell: Cell { col: 0, row: 0 },
+            step_col: 0,
+            step_row: 0,
+            t_max: Vec2::new(N::Float::INFINITY, N::Float::INFINITY),
+            t_delta: Vec2::new(N::Float::INFINITY, N::Float::INFINITY),
+            settled: N::Float::NEG_INFINITY,
+            done: true,
+            seen: self.visited(None),
+            pending: Vec::new(),
+        };
+        if walks {
+            // the outside bucket is not part of any cell along the way
+            iter.test_entities(self.outside.iter().copied());
+            iter.enter_grid();
+        }
+        iter
+    }
+}
+
+impl<T, S: CellStorage, N: Scalar> RaycastIter<'_, T, S, N> {
+    fn enter_grid(&mut self) {
+        let grid = self.spatial_hash;
+        // unbounded grids have nothing to hit beyond the cells used so far
+        let Some((first, last)) = grid.extent else {
+            return;
+        };
+        let min = grid.cell_corner(first.col.into(), first.row.into());
+        let max = grid.cell_corner(i64::from(last.col) + 1, i64::from(last.row) + 1);
+        let Some((t_enter, _)) = intersect(&self.origin, &self.dir, &min, &max) else {
+            return;
+        };
+        if t_enter > self.max_dist {
+            return;
+        }
+        let entry = &self.origin + self.dir.mul(t_enter);
+        let entry = Vec2::new(N::floor_from(entry.x), N::floor_from(entry.y));
+        self.cell = grid.clamp_cell(Cell::unchecked(&entry, grid));
+        let zero = N::Float::ZERO;
+        self.step_col = if self.dir.x > zero {
+            1
+        } else if self.dir.x < zero {
+            -1
+        } else {
+            0
+        };
+        self.step_row = if self.dir.y > zero {
+            1
+        } else if self.dir.y < zero {
+            -1
+        } else {
+            0
+        };
+        // distance along the ray to the next column and row border
+        let next_col = i64::from(self.cell.col) + i64::from(self.step_col.max(0));
+        let next_row = i64::from(self.cell.row) + i64::from(self.step_row.max(0));
+        let border = grid.cell_corner(next_col, next_row);
+        let cell_size = grid.cell_size.to_float();
+        if self.step_col != 0 {
+            self.t_max.x = (border.x - self.origin.x) / self.dir.x;
+            self.t_delta.x = cell_size.x / self.dir.x.abs();
+        }
+        if self.step_row != 0 {
+            self.t_max.y = (border.y - self.origin.y) / self.dir.y;
+            self.t_delta.y = cell_size.y / self.dir.y.abs();
+        }
+        self.done = false;
+    }
+
+    fn test_entities(&mut self, indices: impl Iterator<Item = u32>) {
+        let grid = self.spatial_hash;
+        for index in indices {
+            if !self.seen.first_visit(index) {
+                continue;
+            }
+            let view = grid.view_at(index);
+            let (min, max) = (view.min().to_float(), view.max().to_float());
+            let hit = intersect(&self.origin, &self.dir, &min, &max);
+            if let Some((distance, normal)) = hit
+                && distance <= self.max_dist
+            {
+                self.pending.push(RayHit {
+                    handle: view.handle,
+                    distance,
+                    normal,
+                });
+            }
+        }
+        self.pending
+            .sort_by(|a, b| b.distance.total_cmp(&a.distance));
+    }
+
+    fn visit_cell(&mut self) {
+        let grid = self.spatial_hash;
+        if let Some(vec) = grid.bucket(&self.cell) {
+            self.test_entities(vec.iter().copied());
+        }
+
+        let cell_exit = self.t_max.x.min(self.t_max.y);
+        self.settled = cell_exit;
+        // a ray that never leaves the cell has nothing else to hit
+        if cell_exit > self.max_dist || !cell_exit.is_finite() {
+            self.done = true;
+            return;
+        }
+        // stepping past the last `i32` cell leaves the grid as well
+        let (mut col, mut row) = (Some(self.cell.col), Some(self.cell.row));
+        if self.t_max.x < self.t_max.y {
+            col = self.cell.col.checked_add(self.step_col);
+            self.t_max.x += self.t_delta.x;
+        } else {
+            row = self.cell.row.checked_add(self.step_row);
+            self.t_max.y += self.t_delta.y;
+        }
+        let (Some((first, last)), Some(col), Some(row)) = (grid.extent, col, row) else {
+            self.done = true;
+            return;
+        };
+        self.cell = Cell { col, row };
+        if !(first.col..=last.col).contains(&col) || !(first.row..=last.row).contains(&row) {
+            self.done = true;
+        }
+    }
+}
+
+impl<T, S: CellStorage, N: Scalar> Iterator for RaycastIter<'_, T, S, N> {
+    type Item = RayHit<N::Float>;
+
+    fn next(&mut self) -> Option<RayHit<N::Float>> {
+        loop {
+            if let Some(hit) = self.pending.last()
+                && (self.done || hit.distance <= self.settled)
+            {
+                return self.pending.pop();
+            }
+            if self.done {
+                return None;
+            }
+            self.visit_cell();
+        }
+    }
+}
+
+/// Slab test of the ray against the box from `min` to `max`. Returns the entry
+/// distance and the normal of the side that was entered.
+fn intersect<F: Float>(
+    origin: &Vec2<F>,
+    dir: &Vec2<F>,
+    min: &Vec2<F>,
+    max: &Vec2<F>,
+) -> Option<(F, Vec2<F>)> {
+    let (near_x, far_x) = slab(origin.x, dir.x, min.x, max.x)?;
+    let (near_y, far_y) = slab(origin.y, dir.y, min.y, max.y)?;
+    let near = near_x.max(near_y);
+    let far = far_x.min(far_y);
+    if near > far || far < F::ZERO {
+        return None;
+    }
+    if near < F::ZERO {
+        return Some((F::ZERO, Vec2::new(F::ZERO, F::ZERO)));
+    }
+    let normal = if near_x > near_y {
+        Vec2::new(-dir.x.signum(), F::ZERO)
+    } else {
+        Vec2::new(F::ZERO, -dir.y.signum())
+    };
+    Some((near, normal))
+}
+
+fn slab<F: Float>(origin: F, dir: F, min: F, max: F) -> Option<(F, F)> {
+    if dir == F::ZERO {
+        return if origin < min || origin > max {
+            None
+        } else {
+            Some((F::NEG_INFINITY, F::INFINITY))
+        };
+    }
+    let t1 = (min - origin) / dir;
+    let t2 = (max - origin) / dir;
+    Some((t1.min(t2), t1.max(t2)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn raycast_returns_first_hit_with_normal() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(2.0, 2.0);
+        let far = grid.create(Vec2::new(40.0, 10.0), size.clone(), ())?;
+        let near = grid.create(Vec2::new(20.0, 10.0), size, ())?;
+
+        // Act
+        let hit = grid.raycast(Vec2::new(5.0, 10.0), Vec2::new(3.0, 0.0), 100.0);
+
+        // Assert
+        let hit = hit.unwrap();
+        assert_eq!(hit.handle, near);
+        assert_eq!(hit.distance, 14.0);
+        assert_eq!(hit.normal, Vec2::new(-1.0, 0.0));
+        let hits: Vec<_> = grid
+            .raycast_all(Vec2::new(5.0, 10.0), Vec2::new(1.0, 0.0), 100.0)
+            .map(|hit| hit.handle)
+            .collect();
+        assert_eq!(hits, vec![near, far]);
+        Ok(())
+    }
+
+    #[test]
+    fn raycast_all_orders_hits_from_earlier_cells_correctly() -> anyhow::Result<(), Error> {
+        // Arrange: the wide entity shares the first cell with the ray, but the ray
+        // only climbs into it behind the small one
+        let mut grid = create_grid();
+        let wide = grid.create(Vec2::new(20.0, 3.5), Vec2::new(40.0, 1.0), ())?;
+        let small = grid.create(Vec2::new(6.0, 2.0), Vec2::new(1.0, 1.0), ())?;
+
+        // Act
+        let hits: Vec<_> = grid
+            .raycast_all(Vec2::new(1.0, 1.0), Vec2::new(1.0, 0.2), 100.0)
+            .collect();
+
+        // Assert
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].handle, small);
+        assert_eq!(hits[0].normal, Vec2::new(-1.0, 0.0));
+        assert_eq!(hits[1].handle, wide);
+        assert_eq!(hits[1].normal, Vec2::new(0.0, -1.0));
+        assert!(hits[0].distance < hits[1].distance);
+        Ok(())
+    }
+
+    #[test]
+    fn raycast_from_outside_and_limited_by_distance() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let target = grid.create(Vec2::new(50.0, 5.0), Vec2::new(2.0, 2.0), ())?;
+
+        // Act
+        let from_below = grid.raycast(Vec2::new(50.0, -20.0), Vec2::new(0.0, 1.0), 100.0);
+        let too_short = grid.raycast(Vec2::new(50.0, -20.0), Vec2::new(0.0, 1.0), 20.0);
+        let missing = grid.raycast(Vec2::new(50.0, -20.0), Vec2::new(1.0, 0.0), 100.0);
+        let no_direction = grid.raycast(Vec2::new(50.0, 5.0), Vec2::new(0.0, 0.0), 100.0);
+
+        // Assert
+        let hit = from_below.unwrap();
+        assert_eq!(hit.handle, target);
+        assert_eq!(hit.distance, 24.0);
+        assert_eq!(hit.normal, Vec2::new(0.0, -1.0));
+        assert!(too_short.is_none());
+        assert!(missing.is_none());
+        assert!(no_direction.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn raycast_starting_inside_entity() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let shooter = grid.create(Vec2::new(10.0, 10.0), Vec2::new(2.0, 2.0), ())?;
+        let target = grid.create(Vec2::new(10.0, 30.0), Vec2::new(2.0, 2.0), ())?;
+
+        // Act
+        let hits: Vec<_> = grid
+            .raycast_all(Vec2::new(10.0, 10.0), Vec2::new(0.0, 1.0), 100.0)
+            .collect();
+
+        // Assert
+        assert_eq!(hits[0].handle, shooter);
+        assert_eq!(hits[0].distance, 0.0);
+        assert_eq!(hits[1].handle, target);
+        assert_eq!(hits[1].distance, 19.0);
+        Ok(())
+    }
+
+    #[test]
+    fn raycast_in_unbounded_grid_stops_after_last_cell() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid: SpatialHash = SpatialHash::unbounded(Vec2::new(4.0, 4.0))?;
+        let target = grid.create(Vec2::new(-200.0, 10.0), Vec2::new(2.0, 2.0), ())?;
+
+        // Act
+        let hit = grid.raycast(Vec2::new(0.0, 10.0), Vec2::new(-1.0, 0.0), f32::INFINITY);
+        let away = grid.raycast(Vec2::new(0.0, 10.0), Vec2::new(1.0, 0.0), f32::INFINITY);
+
+        // Assert
+        let hit = hit.unwrap();
+        assert_eq!(hit.handle, target);
+        assert_eq!(hit.distance, 199.0);
+        assert!(away.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn raycast_walks_up_to_the_last_i32_cell() -> anyhow::Result<(), Error> {
+        // Arrange: the box covers the cells up to i32::MAX
+        let mut grid: SpatialHash<(), HashMapStorage, i32> =
+            SpatialHash::unbounded(Vec2::new(1, 1))?;
+        let edge = grid.create(Vec2::new(i32::MAX - 1, 10), Vec2::new(3, 3), ())?;
+        let mut far: SpatialHash = SpatialHash::unbounded(Vec2::new(1.0, 1.0))?;
+        let last = far.create(Vec2::new(2_147_483_520.0, 0.0), Vec2::new(1.0, 1.0), ())?;
+
+        // Act
+        let from_origin: Vec<_> = grid
+            .raycast_all(Vec2::new(0, 10), Vec2::new(1, 0), f64::INFINITY)
+            .collect();
+        let from_inside: Vec<_> = grid
+            .raycast_all(Vec2::new(i32::MAX, 10), Vec2::new(1, 0), f64::INFINITY)
+            .collect();
+        let far_hit = far.raycast(Vec2::new(0.0, 0.0), Vec2::new(1.0, 0.0), f32::INFINITY);
+
+        // Assert
+        assert_eq!(from_origin.len(), 1);
+        assert_eq!(from_origin[0].handle, edge);
+        assert_eq!(from_origin[0].distance, f64::from(i32::MAX - 2));
+        assert_eq!(from_inside.len(), 1);
+        assert_eq!(from_inside[0].distance, 0.0);
+        assert_eq!(far_hit.unwrap().handle, last);
+        Ok(())
+    }
+
+    #[test]
+    fn raycast_handles_huge_tiny_and_zero_directions() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let target = grid.create(Vec2::new(20.0, 10.0), Vec2::new(2.0, 2.0), ())?;
+        let origin = Vec2::new(5.0, 10.0);
+
+        // Act
+        let huge = grid.raycast(origin.clone(), Vec2::new(1e30, 0.0), f32::INFINITY);
+        let huge_finite = grid.raycast(origin.clone(), Vec2::new(1e20, 0.0), 100.0);
+        let tiny = grid.raycast(origin.clone(), Vec2::new(1e-30, 0.0), 100.0);
+        let diagonal = grid
+            .raycast_all(Vec2::new(50.0, 50.0), Vec2::new(1e30, 1e30), f32::INFINITY)
+            .count();
+        let zero = grid
+            .raycast_all(Vec2::new(20.0, 10.0), Vec2::new(0.0, 0.0), f32::INFINITY)
+            .count();
+        let infinite = grid.raycast(origin, Vec2::new(f32::INFINITY, 0.0), 100.0);
+
+        // Assert
+        for hit in [huge, huge_finite, tiny] {
+            let hit = hit.unwrap();
+            assert_eq!(hit.handle, target);
+            assert_eq!(hit.distance, 14.0);
+        }
+        assert_eq!(diagonal, 0);
+        assert_eq!(zero, 0);
+        assert!(infinite.is_none());
+        Ok(())
+    }
+
+    fn create_grid() -> SpatialHash {
+        let cell_size = Vec2::new(4.0, 4.0);
+        let start = Vec2::new(0.0, 0.0);
+        let end = Vec2::new(99.0, 99.0);
+        SpatialHash::new(cell_size, start, end).unwrap()
+    }
+}
@@ -0,0 +1,379 @@
+use crate::error::Error;
+use crate::scalar::Scalar;
+use crate::storage::CellStorage;
+use crate::vec2::*;
+use crate::{
+    Cell, EntityHandle, EntityRecord, Footprint, SpatialHash, bucket_position, bucket_with_room,
+    record_mut,
+};
+
+/// A checked move of one entity: slot index, position, size and footprint.
+pub(crate) type Move<N> = (u32, Vec2<N>, Vec2<N>, Footprint);
+
+/// Frame-static copy of the cells: the slot indices of all cells in one array,
+/// grouped by cell.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SortedCells {
+    // occupied cells ordered by row, then column
+    cells: Vec<Cell>,
+    // the bucket of `cells[i]` is `ids[offsets[i]..offsets[i + 1]]`
+    offsets: Vec<u32>,
+    ids: Vec<u32>,
+    // per-cell counters of `fill_counting`, kept between rebuilds
+    starts: Vec<u32>,
+}
+
+impl PartialEq for SortedCells {
+    fn eq(&self, other: &Self) -> bool {
+        self.cells == other.cells && self.offsets == other.offsets && self.ids == other.ids
+    }
+}
+
+impl SortedCells {
+    pub(crate) fn get(&self, cell: &Cell) -> Option<&[u32]> {
+        let i = self
+            .cells
+            .binary_search_by_key(&(cell.row, cell.col), |cell| (cell.row, cell.col))
+            .ok()?;
+        Some(self.bucket(i))
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (Cell, &[u32])> + '_ {
+        self.cells
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| (*cell, self.bucket(i)))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    fn bucket(&self, i: usize) -> &[u32] {
+        &self.ids[self.offsets[i] as usize..self.offsets[i + 1] as usize]
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.cells.clear();
+        self.offsets.clear();
+        self.ids.clear();
+    }
+
+    /// Appends the cells of `other`, which all have to come after the ones
+    /// already here.
+    pub(crate) fn append(&mut self, other: SortedCells) {
+        if other.cells.is_empty() {
+            return;
+        }
+        let base = self.ids.len() as u32;
+        self.offsets.pop();
+        self.offsets
+            .extend(other.offsets.iter().map(|offset| offset + base));
+        self.cells.extend(other.cells);
+        self.ids.extend(other.ids);
+    }
+
+    /// Counting sort over the row-major cell index, for bounded grids that are
+    /// not much larger than the number of entries.
+    fn fill_counting(&mut self, entries: &[(Cell, u32)], cols: usize, rows: usize) {
+        let key = |cell: &Cell| cell.row as usize * cols + cell.col as usize;
+        let mut starts = std::mem::take(&mut self.starts);
+        starts.clear();
+        starts.resize(cols * rows + 1, 0);
+        for (cell, _) in entries {
+            starts[key(cell) + 1] += 1;
+        }
+        for key in 0..cols * rows {
+            if starts[key + 1] > 0 {
+                self.cells.push(Cell {
+                    col: (key % cols) as i32,
+                    row: (key / cols) as i32,
+                });
+                self.offsets.push(starts[key]);
+            }
+            starts[key + 1] += starts[key];
+        }
+        self.offsets.push(entries.len() as u32);
+        self.ids.resize(entries.len(), 0);
+        for (cell, index) in entries {
+            let next = &mut starts[key(cell)];
+            self.ids[*next as usize] = *index;
+            *next += 1;
+        }
+        self.starts = starts;
+    }
+
+    /// Plain (stable) sort for unbounded or very sparse grids.
+    fn fill_sorted(&mut self, entries: &mut [(Cell, u32)]) {
+        entries.sort_by_key(|(cell, _)| (cell.row, cell.col));
+        self.fill_grouped(entries);
+    }
+
+    /// Takes over entries that are already sorted by row, then column.
+    pub(crate) fn fill_grouped(&mut self, entries: &[(Cell, u32)]) {
+        for (i, (cell, index)) in entries.iter().enumerate() {
+            if self.cells.last() != Some(cell) {
+                self.cells.push(*cell);
+                self.offsets.push(i as u32);
+            }
+            self.ids.push(*index);
+        }
+        self.offsets.push(entries.len() as u32);
+    }
+}
+
+impl<T, S: CellStorage, N: Scalar> SpatialHash<T, S, N> {
+    /// Moves all given entities at once and lays the cells out again from
+    /// scratch, sorted into one contiguous array. Entities that are not listed
+    /// keep their bounds.
+    ///
+    /// Meant for scenes where nearly everything moves every frame. Queries read
+    /// the sorted layout directly; the next `create`, `update` or `remove`
+    /// first moves it back into the cell storage. If any entity is rejected,
+    /// nothing is changed.
+    pub fn rebuild_from(
+        &mut self,
+        entities: impl IntoIterator<Item = (EntityHandle, Vec2<N>, Vec2<N>)>,
+    ) -> anyhow::Result<(), Error> {
+        let mut moves = Vec::new();
+        for (id, pos, size) in entities {
+            moves.push(self.checked_move(id, pos, size)?);
+        }
+        self.reset_layout(moves);
+        let mut entries = self.cell_entries();
+
+        let mut sorted = self.frozen.take().unwrap_or_default();
+        sorted.clear();
+        match &self.num_cells {
+            Some(num_cells)
+                if (num_cells.cols as usize * num_cells.rows as usize) <= 4 * entries.len() =>
+            {
+                sorted.fill_counting(&entries, num_cells.cols as usize, num_cells.rows as usize);
+            }
+            _ => sorted.fill_sorted(&mut entries),
+        }
+        self.frozen = Some(sorted);
+        Ok(())
+    }
+
+    pub(crate) fn checked_move(
+        &self,
+        id: EntityHandle,
+        pos: Vec2<N>,
+        size: Vec2<N>,
+    ) -> anyhow::Result<Move<N>, Error> {
+        self.record(id)?;
+        let footprint = self.footprint(&pos, &size)?;
+        Ok((id.index, pos, size, footprint))
+    }
+
+    /// Applies the moves and empties the cells, leaving only the outside bucket
+    /// and the extent filled in for the new layout.
+    fn reset_layout(&mut self, moves: Vec<Move<N>>) {
+        for (index, pos, size, footprint) in moves {
+            let record = record_mut(&mut self.slots, index);
+            apply_move(record, pos, size, footprint);
+        }
+
+        self.clear_layout();
+        for index in 0..self.slots.len() as u32 {
+            let Some(record) = &self.slots[index as usize].entity else {
+                continue;
+            };
+            let footprint = record.footprint;
+            if footprint.outside {
+                self.push_outside(index);
+            }
+            if let Some((start, end)) = footprint.cells {
+                self.grow_extent(&start, &end);
+            }
+        }
+    }
+
+    /// Empties the cells and the outside bucket, and the extent of unbounded grids.
+    pub(crate) fn clear_layout(&mut self) {
+        let spilled = self.cells.drain().filter(|bucket| bucket.spilled());
+        self.pool.extend(spilled);
+        self.outside.clear();
+        if self.num_cells.is_none() {
+            self.extent = None;
+        }
+    }
+
+    /// A `(cell, slot index)` entry for every footprint cell of every entity,
+    /// in slot order.
+    fn cell_entries(&self) -> Vec<(Cell, u32)> {
+        let mut entries = Vec::new();
+        for (index, slot) in self.slots.iter().enumerate() {
+            if let Some(record) = &slot.entity {
+                entries.extend(footprint_entries(&record.footprint, index as u32));
+            }
+        }
+        entries
+    }
+
+    /// Moves the sorted layout of the last rebuild back into the cell storage,
+    /// so it can be changed entity by entity again.
+    pub(crate) fn thaw(&mut self) {
+        let Some(sorted) = self.frozen.take() else {
+            return;
+        };
+        for (cell, ids) in sorted.iter() {
+            bucket_with_room(&mut self.cells, &mut self.pool, cell, ids.len())
+                .extend_from_slice(ids);
+            for (at, &index) in ids.iter().enumerate() {
+                *bucket_position(&mut self.slots, index, &cell) = at as u32;
+            }
+        }
+    }
+}
+
+/// Gives the entity its new bounds, with room for the bucket positions of
+/// its new footprint.
+pub(crate) fn apply_move<T, N>(
+    record: &mut EntityRecord<T, N>,
+    pos: Vec2<N>,
+    size: Vec2<N>,
+    footprint: Footprint,
+) {
+    record.view.pos = pos;
+    record.view.size = size;
+    record.footprint = footprint;
+    record.positions.resize(footprint.len(), 0);
+}
+
+/// A `(cell, index)` entry for every cell of the footprint, row by row.
+pub(crate) fn footprint_entries(
+    footprint: &Footprint,
+    index: u32,
+) -> impl Iterator<Item = (Cell, u32)> + use<> {
+    footprint.cells.into_iter().flat_map(move |(start, end)| {
+        (start.row..=end.row)
+            .flat_map(move |row| (start.col..=end.col).map(move |col| (Cell { col, row }, index)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::DenseStorage;
+
+    #[test]
+    fn rebuild_moves_entities_and_answers_queries() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(2.0, 2.0);
+        let a = grid.create(Vec2::new(10.0, 10.0), size.clone(), ())?;
+        let b = grid.create(Vec2::new(50.0, 50.0), size.clone(), ())?;
+        let still = grid.create(Vec2::new(80.0, 20.0), size.clone(), ())?;
+
+        // Act
+        grid.rebuild_from([
+            (a, Vec2::new(30.0, 30.0), size.clone()),
+            (b, Vec2::new(31.0, 30.0), size.clone()),
+        ])?;
+
+        // Assert
+        assert!(grid.frozen.is_some());
+        assert_eq!(grid.get(a).unwrap().pos(), &Vec2::new(30.0, 30.0));
+        let mut found = grid.query_rect(Vec2::new(28.0, 28.0), Vec2::new(33.0, 33.0));
+        found.sort_by_key(|handle| handle.index());
+        assert_eq!(found, vec![a, b]);
+        assert!(
+            grid.query_rect(Vec2::new(8.0, 8.0), Vec2::new(12.0, 12.0))
+                .is_empty()
+        );
+        assert_eq!(grid.query_radius(Vec2::new(80.0, 20.0), 0.5), vec![still]);
+        assert_eq!(grid.k_nearest(Vec2::new(0.0, 0.0), 1)[0].0, a);
+        assert_eq!(grid.collision_pairs().collect::<Vec<_>>(), vec![(a, b)]);
+        let hit = grid.raycast(Vec2::new(0.0, 30.0), Vec2::new(1.0, 0.0), 100.0);
+        assert_eq!(hit.unwrap().handle, a);
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_rebuild_changes_nothing() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut grid = create_grid();
+        let size = Vec2::new(2.0, 2.0);
+        let a = grid.create(Vec2::new(10.0, 10.0), size.clone(), ())?;
+        let b = grid.create(Vec2::new(50.0, 50.0), size.clone(), ())?;
+
+        // Act
+        let res = grid.rebuild_from([
+            (a, Vec2::new(30.0, 30.0), size.clone()),
+            (b, Vec2::new(300.0, 30.0), size),
+        ]);
+
+        // Assert
+        assert!(matches!(res, Err(Error::OutOfBounds)));
+        assert!(grid.frozen.is_none());
+        assert_eq!(grid.get(a).unwrap().pos(), &Vec2::new(10.0, 10.0));
+        assert_eq!(
+            grid.query_rect(Vec2::new(9.0, 9.0), Vec2::new(11.0, 11.0)),
+            vec![a]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn changes_after_rebuild_thaw_the_layout() -> anyhow::Result<(), Error> {
+        // a small dense grid takes the counting sort path, the unbounded one the plain sort
+        let dense = SpatialHash::<(), DenseStorage>::with_storage(
+            Vec2::new(10.0, 10.0),
+            Vec2::new(0.0, 0.0),
+            Vec2::new(29.0, 29.0),
+        )?;
+        let dense = change_after_rebuild(dense)?;
+        assert_eq!(dense.cells.len(), 4);
+        change_after_rebuild(SpatialHash::unbounded(Vec2::new(10.0, 10.0))?)?;
+        Ok(())
+    }
+
+    fn change_after_rebuild<S: CellStorage>(
+        mut grid: SpatialHash<(), S>,
+    ) -> anyhow::Result<SpatialHash<(), S>, Error> {
+        // Arrange
+        let size = Vec2::new(4.0, 4.0);
+        let a = grid.create(Vec2::new(5.0, 5.0), size.clone(), ())?;
+        let b = grid.create(Vec2::new(15.0, 5.0), size.clone(), ())?;
+        let c = grid.create(Vec2::new(25.0, 25.0), size.clone(), ())?;
+        grid.rebuild_from([(a, Vec2::new(10.0, 10.0), size)])?;
+
+        // Act
+        grid.remove(c)?;
+        grid.set_position(b, Vec2::new(12.0, 8.0))?;
+
+        // Assert
+        assert!(grid.frozen.is_none());
+        let mut found = grid.query_rect(Vec2::new(0.0, 0.0), Vec2::new(29.0, 29.0));
+        found.sort_by_key(|handle| handle.index());
+        assert_eq!(found, vec![a, b]);
+        Ok(grid)
+    }
+
+    #[test]
+    fn rebuild_keeps_spilled_buckets_for_later() -> anyhow::Result<(), Error> {
+        // Arrange: more entities in one cell than a bucket holds inline
+        let mut grid = create_grid();
+        let handles = (0..10)
+            .map(|_| grid.create(Vec2::new(10.0, 10.0), Vec2::new(1.0, 1.0), ()))
+            .collect::<anyhow::Result<Vec<_>, Error>>()?;
+
+        // Act
+        grid.rebuild_from([])?;
+
+        // Assert
+        assert_eq!(grid.pool.len(), 1);
+        grid.set_position(handles[0], Vec2::new(50.0, 50.0))?;
+        assert!(grid.pool.is_empty(), "thawing takes the bucket back");
+        Ok(())
+    }
+
+    fn create_grid() -> SpatialHash {
+        let cell_size = Vec2::new(4.0, 4.0);
+        let start = Vec2::new(0.0, 0.0);
+        let end = Vec2::new(99.0, 99.0);
+        SpatialHash::new(cell_size, start, end).unwrap()
+    }
+}
@@ -0,0 +1,310 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
+
+/// Number type of coordinates and sizes: `f32` (the default), `f64`, `i32`
+/// or `i64`.
+///
+/// Integer grids find cells with integer division instead of `floor()`, `f64`
+/// grids stay accurate far away from the origin. Distances are measured in
+/// [`Scalar::Float`]. Integer boxes cover exactly `size` tiles, `size / 2` of
+/// them below `pos`: a box of size 1 covers just the tile it sits on, one of
+/// size 2 at 10 the tiles 9 and 10.
+pub trait Scalar:
+    Copy
+    + Debug
+    + Default
+    + PartialOrd
+    + Send
+    + Sync
+    + 'static
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + AddAssign
+    + SubAssign
+    + MulAssign
+    + DivAssign
+{
+    /// Floating point type distances are measured in.
+    type Float: Float;
+
+    const ZERO: Self;
+    const ONE: Self;
+    const TWO: Self;
+    /// Sizes up to this count as zero.
+    const EPSILON: Self;
+    /// Taken off both ends of a bounding box, so a box ending right on a cell
+    /// border does not reach into the next cell.
+    const NUDGE: Self;
+
+    /// Index of the cell `pos` falls into, for cells of `size` starting at
+    /// `start`: `floor((pos - start) / size)`, saturated to `i64`. Cells are
+    /// numbered with `i32`, so callers still have to check that it fits.
+    fn cell_index(pos: Self, start: Self, size: Self) -> i64;
+
+    /// Number of cells of `size` it takes to cover `len`, zero if `len` or
+    /// `size` is not positive.
+    fn cells_to_cover(len: Self, size: Self) -> u32;
+
+    fn from_index(index: i64) -> Self;
+
+    /// Lowest and highest coordinate of a box of `size` around `pos`, `None`
+    /// if they do not fit into `Self`.
+    fn box_bounds(pos: Self, size: Self) -> Option<(Self, Self)>;
+
+    /// `self + other`, `None` if that does not fit into `Self`.
+    fn checked_add(self, other: Self) -> Option<Self>;
+
+    /// `self - other`, `None` if that does not fit into `Self`.
+    fn checked_sub(self, other: Self) -> Option<Self>;
+
+    /// `self * other`, `None` if that does not fit into `Self`.
+    fn checked_mul(self, other: Self) -> Option<Self>;
+
+    fn to_float(self) -> Self::Float;
+
+    /// Largest value not above `value`.
+    fn floor_from(value: Self::Float) -> Self;
+
+    /// Smallest value not below `value`.
+    fn ceil_from(value: Self::Float) -> Self;
+
+    fn abs(self) -> Self;
+
+    fn is_finite(self) -> bool;
+}
+
+/// Floating point scalar, the type distances come in.
+pub trait Float: Scalar<Float = Self> + Neg<Output = Self> {
+    const INFINITY: Self;
+    const NEG_INFINITY: Self;
+
+    fn sqrt(self) -> Self;
+
+    fn max(self, other: Self) -> Self;
+
+    fn min(self, other: Self) -> Self;
+
+    fn floor(self) -> Self;
+
+    fn ceil(self) -> Self;
+
+    fn signum(self) -> Self;
+
+    fn total_cmp(&self, other: &Self) -> Ordering;
+}
+
+macro_rules! float_scalar {
+    ($float:ident) => {
+        impl Scalar for $float {
+            type Float = $float;
+
+            const ZERO: Self = 0.0;
+            const ONE: Self = 1.0;
+            const TWO: Self = 2.0;
+            const EPSILON: Self = $float::EPSILON;
+            // TODO replace 0.0001 with something sensible.
+            const NUDGE: Self = 0.0001;
+
+            fn cell_index(pos: Self, start: Self, size: Self) -> i64 {
+                ((pos - start) / size).floor() as i64
+            }
+
+            fn cells_to_cover(len: Self, size: Self) -> u32 {
+                // also turns NaN into zero cells
+                (len / size).ceil() as u32
+            }
+
+            fn from_index(index: i64) -> Self {
+                index as $float
+            }
+
+            fn box_bounds(pos: Self, size: Self) -> Option<(Self, Self)> {
+                // floats overflow into infinity, which the bounds checks refuse
+                Some((pos - size / 2.0, pos + size / 2.0))
+            }
+
+            fn checked_add(self, other: Self) -> Option<Self> {
+                Some(self + other)
+            }
+
+            fn checked_sub(self, other: Self) -> Option<Self> {
+                Some(self - other)
+            }
+
+            fn checked_mul(self, other: Self) -> Option<Self> {
+                Some(self * other)
+            }
+
+            fn to_float(self) -> Self {
+                self
+            }
+
+            fn floor_from(value: Self) -> Self {
+                value
+            }
+
+            fn ceil_from(value: Self) -> Self {
+                value
+            }
+
+            fn abs(self) -> Self {
+                $float::abs(self)
+            }
+
+            fn is_finite(self) -> bool {
+                $float::is_finite(self)
+            }
+        }
+
+        impl Float for $float {
+            const INFINITY: Self = $float::INFINITY;
+            const NEG_INFINITY: Self = $float::NEG_INFINITY;
+
+            fn sqrt(self) -> Self {
+                $float::sqrt(self)
+            }
+
+            fn max(self, other: Self) -> Self {
+                $float::max(self, other)
+            }
+
+            fn min(self, other: Self) -> Self {
+                $float::min(self, other)
+            }
+
+            fn floor(self) -> Self {
+                $float::floor(self)
+            }
+
+            fn ceil(self) -> Self {
+                $float::ceil(self)
+            }
+
+            fn signum(self) -> Self {
+                $float::signum(self)
+            }
+
+            fn total_cmp(&self, other: &Self) -> Ordering {
+                $float::total_cmp(self, other)
+            }
+        }
+    };
+}
+
+macro_rules! integer_scalar {
+    ($int:ident, $wide:ident) => {
+        impl Scalar for $int {
+            type Float = f64;
+
+            const ZERO: Self = 0;
+            const ONE: Self = 1;
+            const TWO: Self = 2;
+            const EPSILON: Self = 0;
+            const NUDGE: Self = 0;
+
+            fn cell_index(pos: Self, start: Self, size: Self) -> i64 {
+                // wide enough that `pos - start` can not overflow
+                let index = ($wide::from(pos) - $wide::from(start)).div_euclid($wide::from(size));
+                i64::try_from(index).unwrap_or(if index < 0 { i64::MIN } else { i64::MAX })
+            }
+
+            fn cells_to_cover(len: Self, size: Self) -> u32 {
+                if len <= 0 || size <= 0 {
+                    return 0;
+                }
+                let cells = ($wide::from(len) + $wide::from(size) - 1) / $wide::from(size);
+                Ord::min(cells, u32::MAX.into()) as u32
+            }
+
+            fn from_index(index: i64) -> Self {
+                index as $int
+            }
+
+            fn box_bounds(pos: Self, size: Self) -> Option<(Self, Self)> {
+                let min = pos.checked_sub(size / 2)?;
+                let max = min.checked_add(Ord::max(size.saturating_sub(1), 0))?;
+                Some((min, max))
+            }
+
+            fn checked_add(self, other: Self) -> Option<Self> {
+                $int::checked_add(self, other)
+            }
+
+            fn checked_sub(self, other: Self) -> Option<Self> {
+                $int::checked_sub(self, other)
+            }
+
+            fn checked_mul(self, other: Self) -> Option<Self> {
+                $int::checked_mul(self, other)
+            }
+
+            fn to_float(self) -> f64 {
+                self as f64
+            }
+
+            fn floor_from(value: f64) -> Self {
+                value.floor() as $int
+            }
+
+            fn ceil_from(value: f64) -> Self {
+                value.ceil() as $int
+            }
+
+            fn abs(self) -> Self {
+                self.saturating_abs()
+            }
+
+            fn is_finite(self) -> bool {
+                true
+            }
+        }
+    };
+}
+
+float_scalar!(f32);
+float_scalar!(f64);
+integer_scalar!(i32, i64);
+integer_scalar!(i64, i128);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_cells_round_towards_negative_infinity() {
+        assert_eq!(i32::cell_index(7, 0, 4), 1);
+        assert_eq!(i32::cell_index(-1, 0, 4), -1);
+        assert_eq!(i32::cell_index(-4, 0, 4), -1);
+        assert_eq!(i32::cell_index(-5, 0, 4), -2);
+        assert_eq!(i32::cell_index(i32::MAX, i32::MIN, 1), u32::MAX.into());
+        assert_eq!(i64::cell_index(i64::MIN, 0, 1), i64::MIN);
+        assert_eq!(i64::cell_index(i64::MAX, i64::MIN, 1), i64::MAX);
+        assert_eq!(f32::cell_index(-0.5, 0.0, 4.0), -1);
+        assert_eq!(f32::cell_index(3e9, 0.0, 1.0), 3_000_000_000);
+    }
+
+    #[test]
+    fn integer_boxes_cover_exactly_their_size() {
+        assert_eq!(i32::box_bounds(10, 1), Some((10, 10)));
+        assert_eq!(i32::box_bounds(10, 2), Some((9, 10)));
+        assert_eq!(i32::box_bounds(10, 3), Some((9, 11)));
+        assert_eq!(i64::box_bounds(10, 4), Some((8, 11)));
+        assert_eq!(i32::box_bounds(10, 0), Some((10, 10)));
+        assert_eq!(i32::box_bounds(i32::MAX, 4), None);
+        assert_eq!(i32::box_bounds(i32::MIN, 4), None);
+        assert_eq!(f32::box_bounds(10.0, 2.0), Some((9.0, 11.0)));
+    }
+
+    #[test]
+    fn cells_to_cover_rounds_up() {
+        assert_eq!(i32::cells_to_cover(100, 4), 25);
+        assert_eq!(i32::cells_to_cover(101, 4), 26);
+        assert_eq!(i64::cells_to_cover(0, 4), 0);
+        assert_eq!(i32::cells_to_cover(10, 0), 0);
+        assert_eq!(f64::cells_to_cover(100.5, 4.0), 26);
+        assert_eq!(f32::cells_to_cover(f32::NAN, 4.0), 0);
+    }
+}
@@ -0,0 +1,326 @@
+use crate::error::Error;
+use crate::scalar::Scalar;
+use crate::storage::{CellStorage, HashMapStorage};
+use crate::vec2::Vec2;
+use crate::{EntityHandle, SpatialHash};
+use arc_swap::ArcSwap;
+use std::sync::Arc;
+
+/// A [`SpatialHash`] that the simulation writes while readers query the last
+/// published frame.
+///
+/// Writers change the back buffer and hand the finished frame to the readers
+/// with [`DoubleBuffered::publish`]. Readers hold on to a whole frame as an
+/// `Arc` snapshot and query it without any locking; loading the newest frame
+/// is an atomic pointer read and never waits for the writer.
+///
+/// Publishing swaps the buffers: the frame readers saw so far becomes the new
+/// back buffer and catches up by replaying the changes made through
+/// [`DoubleBuffered::create`], [`DoubleBuffered::update`] and friends, so a
+/// publish costs about the number of changes in the frame. Two cases copy the
+/// whole grid instead, at O(entities + occupied cells): a frame changed through
+/// [`DoubleBuffered::back_mut`], whose changes are not recorded, and a previous
+/// frame some reader still holds a snapshot of.
+#[derive(Debug)]
+pub struct DoubleBuffered<T = (), S = HashMapStorage, N = f32> {
+    // never shared; the `Arc` only lets it be published without a copy
+    back: Arc<SpatialHash<T, S, N>>,
+    front: Arc<ArcSwap<SpatialHash<T, S, N>>>,
+    // changes made to the back buffer since the last publish
+    journal: Vec<Change<T, N>>,
+    // set by `back_mut`, whose changes the journal misses
+    copy_needed: bool,
+}
+
+#[derive(Debug)]
+enum Change<T, N> {
+    Create(Vec2<N>, Vec2<N>, T),
+    Update(EntityHandle, Vec2<N>, Vec2<N>),
+    Remove(EntityHandle),
+}
+
+/// Handle for reader threads, see [`DoubleBuffered::reader`].
+#[derive(Debug)]
+pub struct SnapshotReader<T = (), S = HashMapStorage, N = f32> {
+    front: Arc<ArcSwap<SpatialHash<T, S, N>>>,
+}
+
+impl<T: Clone, S: CellStorage + Clone, N: Scalar> DoubleBuffered<T, S, N> {
+    /// Starts with `grid` as the back buffer and a copy of it published.
+    pub fn new(grid: SpatialHash<T, S, N>) -> Self {
+        let front = Arc::new(ArcSwap::from_pointee(grid.clone()));
+        Self {
+            back: Arc::new(grid),
+            front,
+            journal: Vec::new(),
+            copy_needed: false,
+        }
+    }
+
+    /// The frame being written.
+    pub fn back(&self) -> &SpatialHash<T, S, N> {
+        &self.back
+    }
+
+    /// Direct access to the frame being written. Changes made through it are
+    /// not recorded, so the next publish copies the whole grid.
+    pub fn back_mut(&mut self) -> &mut SpatialHash<T, S, N> {
+        self.copy_needed = true;
+        self.back_frame()
+    }
+
+    /// [`SpatialHash::create`] on the back buffer.
+    pub fn create(
+        &mut self,
+        pos: Vec2<N>,
+        size: Vec2<N>,
+        data: T,
+    ) -> anyhow::Result<EntityHandle, Error> {
+        let handle = self
+            .back_frame()
+            .create(pos.clone(), size.clone(), data.clone())?;
+        self.journal.push(Change::Create(pos, size, data));
+        Ok(handle)
+    }
+
+    /// [`SpatialHash::update`] on the back buffer.
+    pub fn update(
+        &mut self,
+        id: EntityHandle,
+        pos: Vec2<N>,
+        size: Vec2<N>,
+    ) -> anyhow::Result<(), Error> {
+        self.back_frame().update(id, pos.clone(), size.clone())?;
+        self.journal.push(Change::Update(id, pos, size));
+        Ok(())
+    }
+
+    /// [`SpatialHash::set_position`] on the back buffer.
+    pub fn set_position(&mut self, id: EntityHandle, pos: Vec2<N>) -> anyhow::Result<(), Error> {
+        let size = self.back.get(id).ok_or(Error::StaleHandle)?.size.clone();
+        self.update(id, pos, size)
+    }
+
+    /// [`SpatialHash::remove`] on the back buffer.
+    pub fn remove(&mut self, id: EntityHandle) -> anyhow::Result<T, Error> {
+        let data = self.back_frame().remove(id)?;
+        self.journal.push(Change::Remove(id));
+        Ok(data)
+    }
+
+    /// Makes the back buffer the frame readers see from now on. Readers still
+    /// holding an older snapshot keep it unchanged.
+    ///
+    /// The frame published before becomes the back buffer and replays this
+    /// frame's changes; see the type docs for when it is copied instead.
+    pub fn publish(&mut self) {
+        let previous = self.front.swap(Arc::clone(&self.back));
+        let published = std::mem::replace(&mut self.back, previous);
+        let journal = std::mem::take(&mut self.journal);
+        match Arc::get_mut(&mut self.back) {
+            Some(frame) if !self.copy_needed => {
+                for change in journal {
+                    let replayed = match change {
+                        Change::Create(pos, size, data) => frame.create(pos, size, data).map(drop),
+                        Change::Update(id, pos, size) => frame.update(id, pos, size),
+                        Change::Remove(id) => frame.remove(id).map(drop),
+                    };
+                    replayed.expect("the change succeeded on an identical frame");
+                }
+            }
+            Some(frame) => frame.clone_from(&published),
+            None => self.back = Arc::new((*published).clone()),
+        }
+        self.copy_needed = false;
+    }
+
+    /// The frame published last.
+    pub fn snapshot(&self) -> Arc<SpatialHash<T, S, N>> {
+        self.front.load_full()
+    }
+
+    /// A handle other threads can load the published frames through.
+    pub fn reader(&self) -> SnapshotReader<T, S, N> {
+        SnapshotReader {
+            front: Arc::clone(&self.front),
+        }
+    }
+
+    fn back_frame(&mut self) -> &mut SpatialHash<T, S, N> {
+        Arc::get_mut(&mut self.back).expect("the back buffer is never shared")
+    }
+}
+
+impl<T, S, N> SnapshotReader<T, S, N> {
+    /// The frame published last. Loading it never waits for the writer.
+    pub fn snapshot(&self) -> Arc<SpatialHash<T, S, N>> {
+        self.front.load_full()
+    }
+}
+
+impl<T, S, N> Clone for SnapshotReader<T, S, N> {
+    fn clone(&self) -> Self {
+        Self {
+            front: Arc::clone(&self.front),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::vec2::*;
+
+    #[test]
+    fn readers_see_published_frames_only() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut buffered = DoubleBuffered::new(create_grid());
+        let reader = buffered.reader();
+        let a = buffered.create(Vec2::new(10.0, 10.0), Vec2::new(2.0, 2.0), ())?;
+        let before = reader.snapshot();
+
+        // Act
+        buffered.publish();
+        buffered.set_position(a, Vec2::new(50.0, 50.0))?;
+
+        // Assert
+        assert!(!before.contains(a), "old snapshots stay as they were");
+        let published = reader.snapshot();
+        assert_eq!(published.get(a).unwrap().pos(), &Vec2::new(10.0, 10.0));
+        assert_eq!(
+            published.query_rect(Vec2::new(9.0, 9.0), Vec2::new(11.0, 11.0)),
+            vec![a]
+        );
+        buffered.publish();
+        assert_eq!(
+            reader.snapshot().get(a).unwrap().pos(),
+            &Vec2::new(50.0, 50.0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn publish_swaps_the_buffers() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut buffered = DoubleBuffered::new(create_grid());
+        let first = buffered.snapshot();
+        let a = buffered.create(Vec2::new(10.0, 10.0), Vec2::new(2.0, 2.0), ())?;
+        let first_ptr = Arc::as_ptr(&first);
+        drop(first);
+
+        // Act
+        buffered.publish();
+
+        // Assert: the frame readers let go of is written again
+        assert!(std::ptr::eq(buffered.back(), first_ptr));
+        assert!(buffered.back().contains(a));
+        assert!(buffered.snapshot().contains(a));
+        Ok(())
+    }
+
+    #[test]
+    fn replayed_changes_match_the_published_frame() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut buffered = DoubleBuffered::new(create_grid());
+        let a = buffered.create(Vec2::new(10.0, 10.0), Vec2::new(2.0, 2.0), ())?;
+        let b = buffered.create(Vec2::new(20.0, 20.0), Vec2::new(2.0, 2.0), ())?;
+        buffered.publish();
+        buffered.update(a, Vec2::new(30.0, 30.0), Vec2::new(9.0, 9.0))?;
+        buffered.remove(b)?;
+        let c = buffered.create(Vec2::new(60.0, 60.0), Vec2::new(1.0, 1.0), ())?;
+        assert!(buffered.set_position(b, Vec2::new(1.0, 1.0)).is_err());
+
+        // Act
+        buffered.publish();
+
+        // Assert
+        let front = buffered.snapshot();
+        let back = buffered.back();
+        for id in [a, b, c] {
+            assert_eq!(back.get(id), front.get(id));
+        }
+        let everything = (Vec2::new(0.0, 0.0), Vec2::new(99.0, 99.0));
+        assert_eq!(
+            back.query_rect(everything.0.clone(), everything.1.clone()),
+            front.query_rect(everything.0, everything.1)
+        );
+        assert_eq!(c.index(), b.index(), "both buffers reuse the same slot");
+        let d = buffered.create(Vec2::new(5.0, 5.0), Vec2::new(1.0, 1.0), ())?;
+        buffered.publish();
+        assert_eq!(buffered.back().get(d), buffered.snapshot().get(d));
+        Ok(())
+    }
+
+    #[test]
+    fn held_frames_and_direct_changes_are_copied() -> anyhow::Result<(), Error> {
+        // Arrange
+        let mut buffered = DoubleBuffered::new(create_grid());
+        let held = buffered.snapshot();
+        let a = buffered
+            .back_mut()
+            .create(Vec2::new(10.0, 10.0), Vec2::new(2.0, 2.0), ())?;
+
+        // Act
+        buffered.publish();
+
+        // Assert: a frame a reader still holds is never written over
+        assert!(!held.contains(a));
+        assert!(!std::ptr::eq(buffered.back(), Arc::as_ptr(&held)));
+        assert!(buffered.back().contains(a));
+        drop(held);
+        buffered.back_mut().remove(a)?;
+        buffered.publish();
+        assert!(!buffered.back().contains(a));
+        assert!(!buffered.snapshot().contains(a));
+        Ok(())
+    }
+
+    #[test]
+    fn readers_always_see_whole_frames() -> anyhow::Result<(), Error> {
+        // Arrange: every frame moves all entities to the same column
+        let mut buffered = DoubleBuffered::new(create_grid());
+        let handles = (0..50)
+            .map(|i| {
+                buffered.create(
+                    Vec2::new(5.0, 1.5 * i as f32 + 5.0),
+                    Vec2::new(1.0, 1.0),
+                    (),
+                )
+            })
+            .collect::<anyhow::Result<Vec<_>, Error>>()?;
+        buffered.publish();
+        let reader = buffered.reader();
+
+        // Act & Assert
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for frame in 0..200 {
+                    let x = 5.0 + (frame % 90) as f32;
+                    for &handle in &handles {
+                        let y = buffered.back().get(handle).unwrap().pos().y;
+                        buffered.set_position(handle, Vec2::new(x, y)).unwrap();
+                    }
+                    buffered.publish();
+                }
+            });
+            scope.spawn(|| {
+                for _ in 0..200 {
+                    let frame = reader.snapshot();
+                    let x = frame.get(handles[0]).unwrap().pos().x;
+                    assert!(handles.iter().all(|&h| frame.get(h).unwrap().pos().x == x));
+                    let column = frame.query_rect(Vec2::new(x, 0.0), Vec2::new(x, 99.0));
+                    assert_eq!(column.len(), handles.len());
+                }
+            });
+        });
+        Ok(())
+    }
+
+    fn create_grid() -> SpatialHash {
+        let cell_size = Vec2::new(4.0, 4.0);
+        let start = Vec2::new(0.0, 0.0);
+        let end = Vec2::new(99.0, 99.0);
+        SpatialHash::new(cell_size, start, end).unwrap()
+    }
+}
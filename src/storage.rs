@@ -0,0 +1,239 @@
+use crate::error::Error;
+use crate::{Cell, Dimensions};
+use smallvec::SmallVec;
+use std::collections::{BTreeMap, HashMap};
+
+/// Slot indices of the entities in one cell. Up to four of them fit without
+/// a heap allocation.
+pub type Bucket = SmallVec<[u32; 4]>;
+
+/// Where a [`SpatialHash`](crate::SpatialHash) keeps the slot indices of the
+/// entities in each cell.
+///
+/// Only occupied cells have a bucket: a bucket is created right before an
+/// entity is pushed into it and removed once it runs empty.
+pub trait CellStorage {
+    /// Storage for a grid of `num_cells` cells, `None` if the grid is unbounded.
+    fn for_grid(num_cells: Option<&Dimensions>) -> anyhow::Result<Self, Error>
+    where
+        Self: Sized;
+
+    fn get(&self, cell: &Cell) -> Option<&Bucket>;
+
+    fn get_mut(&mut self, cell: &Cell) -> Option<&mut Bucket>;
+
+    /// Bucket of the cell, created empty if the cell is unoccupied.
+    fn get_or_create(&mut self, cell: Cell) -> &mut Bucket;
+
+    /// Removes the bucket of the cell and hands it back.
+    fn remove(&mut self, cell: &Cell) -> Option<Bucket>;
+
+    /// Removes every bucket and hands them back.
+    fn drain(&mut self) -> impl Iterator<Item = Bucket> + '_;
+
+    /// Every occupied cell with its bucket. Queries count on this costing
+    /// about as much as there are occupied cells, not cells in the grid.
+    fn iter(&self) -> impl Iterator<Item = (Cell, &Bucket)> + '_;
+
+    /// Number of occupied cells.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains_key(&self, cell: &Cell) -> bool {
+        self.get(cell).is_some()
+    }
+
+    fn values(&self) -> impl Iterator<Item = &Bucket> + '_ {
+        self.iter().map(|(_, vec)| vec)
+    }
+}
+
+/// Cells in a `HashMap`, only paying for occupied cells. Works for every grid.
+#[derive(Debug, Default, Clone)]
+pub struct HashMapStorage(HashMap<Cell, Bucket>);
+
+impl CellStorage for HashMapStorage {
+    fn for_grid(_: Option<&Dimensions>) -> anyhow::Result<Self, Error> {
+        Ok(Self::default())
+    }
+
+    fn get(&self, cell: &Cell) -> Option<&Bucket> {
+        self.0.get(cell)
+    }
+
+    fn get_mut(&mut self, cell: &Cell) -> Option<&mut Bucket> {
+        self.0.get_mut(cell)
+    }
+
+    fn get_or_create(&mut self, cell: Cell) -> &mut Bucket {
+        self.0.entry(cell).or_default()
+    }
+
+    fn remove(&mut self, cell: &Cell) -> Option<Bucket> {
+        self.0.remove(cell)
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = Bucket> + '_ {
+        self.0.drain().map(|(_, vec)| vec)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Cell, &Bucket)> + '_ {
+        self.0.iter().map(|(cell, vec)| (*cell, vec))
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Most cells a [`DenseStorage`] allocates buckets for. Larger grids are
+/// refused with [`Error::TooManyCells`]; they are better off in a
+/// [`HashMapStorage`], which only pays for occupied cells.
+pub const MAX_DENSE_CELLS: usize = 1 << 24;
+
+/// One bucket per cell of a bounded grid in a flat array indexed by
+/// `row * cols + col`. Lookups skip hashing at the cost of memory for every
+/// cell, empty or not. The occupied cells are listed separately, so going
+/// through them costs as much as in the other storages.
+#[derive(Debug, Clone)]
+pub struct DenseStorage {
+    cols: usize,
+    rows: usize,
+    buckets: Vec<Option<Bucket>>,
+    // array index of every occupied cell, in no particular order
+    occupied: Vec<usize>,
+    // where each occupied cell is listed in `occupied`
+    listed_at: Vec<usize>,
+}
+
+impl DenseStorage {
+    fn index(&self, cell: &Cell) -> Option<usize> {
+        let col = usize::try_from(cell.col)
+            .ok()
+            .filter(|&col| col < self.cols)?;
+        let row = usize::try_from(cell.row)
+            .ok()
+            .filter(|&row| row < self.rows)?;
+        Some(row * self.cols + col)
+    }
+
+    fn cell_at(&self, index: usize) -> Cell {
+        Cell {
+            col: (index % self.cols) as i32,
+            row: (index / self.cols) as i32,
+        }
+    }
+}
+
+impl CellStorage for DenseStorage {
+    fn for_grid(num_cells: Option<&Dimensions>) -> anyhow::Result<Self, Error> {
+        let num_cells = num_cells.ok_or(Error::StorageNeedsBounds)?;
+        let (cols, rows) = (num_cells.cols as usize, num_cells.rows as usize);
+        let len = cols
+            .checked_mul(rows)
+            .filter(|&len| len <= MAX_DENSE_CELLS)
+            .ok_or(Error::TooManyCells)?;
+        let mut buckets = Vec::new();
+        buckets.resize_with(len, || None);
+        Ok(Self {
+            cols,
+            rows,
+            buckets,
+            occupied: Vec::new(),
+            listed_at: vec![0; len],
+        })
+    }
+
+    fn get(&self, cell: &Cell) -> Option<&Bucket> {
+        self.buckets[self.index(cell)?].as_ref()
+    }
+
+    fn get_mut(&mut self, cell: &Cell) -> Option<&mut Bucket> {
+        let index = self.index(cell)?;
+        self.buckets[index].as_mut()
+    }
+
+    fn get_or_create(&mut self, cell: Cell) -> &mut Bucket {
+        let index = self.index(&cell).expect("cell inside the dense grid");
+        self.buckets[index].get_or_insert_with(|| {
+            self.listed_at[index] = self.occupied.len();
+            self.occupied.push(index);
+            Bucket::new()
+        })
+    }
+
+    fn remove(&mut self, cell: &Cell) -> Option<Bucket> {
+        let index = self.index(cell)?;
+        let bucket = self.buckets[index].take()?;
+        let at = self.listed_at[index];
+        self.occupied.swap_remove(at);
+        if let Some(&moved) = self.occupied.get(at) {
+            self.listed_at[moved] = at;
+        }
+        Some(bucket)
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = Bucket> + '_ {
+        self.occupied.drain(..).map(|index| {
+            self.buckets[index]
+                .take()
+                .expect("listed cells are occupied")
+        })
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Cell, &Bucket)> + '_ {
+        self.occupied.iter().map(|&index| {
+            let vec = self.buckets[index]
+                .as_ref()
+                .expect("listed cells are occupied");
+            (self.cell_at(index), vec)
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.occupied.len()
+    }
+}
+
+/// Cells in a `BTreeMap`, so iterating them (and with that
+/// [`collision_pairs`](crate::SpatialHash::collision_pairs)) always happens in
+/// the same order.
+#[derive(Debug, Default, Clone)]
+pub struct BTreeStorage(BTreeMap<Cell, Bucket>);
+
+impl CellStorage for BTreeStorage {
+    fn for_grid(_: Option<&Dimensions>) -> anyhow::Result<Self, Error> {
+        Ok(Self::default())
+    }
+
+    fn get(&self, cell: &Cell) -> Option<&Bucket> {
+        self.0.get(cell)
+    }
+
+    fn get_mut(&mut self, cell: &Cell) -> Option<&mut Bucket> {
+        self.0.get_mut(cell)
+    }
+
+    fn get_or_create(&mut self, cell: Cell) -> &mut Bucket {
+        self.0.entry(cell).or_default()
+    }
+
+    fn remove(&mut self, cell: &Cell) -> Option<Bucket> {
+        self.0.remove(cell)
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = Bucket> + '_ {
+        std::mem::take(&mut self.0).into_values()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Cell, &Bucket)> + '_ {
+        self.0.iter().map(|(cell, vec)| (*cell, vec))
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
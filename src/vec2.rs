@@ -1,48 +1,60 @@
+#![allow(clippy::needless_arbitrary_self_type, clippy::needless_lifetimes)]
+
+use crate::scalar::{Float, Scalar};
 use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Sub, SubAssign};
 
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
-pub struct Vec2 {
-    pub x: f32,
-    pub y: f32,
+pub struct Vec2<N = f32> {
+    pub x: N,
+    pub y: N,
 }
-impl Vec2 {
-    pub fn new(x: f32, y: f32) -> Self {
+impl<N: Scalar> Vec2<N> {
+    pub fn new(x: N, y: N) -> Self {
         Self { x, y }
     }
-    pub fn ceil(self: Self) -> Self {
-        Self {
-            x: self.x.ceil(),
-            y: self.y.ceil(),
-        }
-    }
-    pub fn add(self: &Self, other: f32) -> Self {
+    pub fn add(self: &Self, other: N) -> Self {
         Self {
             x: self.x + other,
             y: self.y + other,
         }
     }
-    pub fn sub(self: &Self, other: f32) -> Self {
+    pub fn sub(self: &Self, other: N) -> Self {
         Self {
             x: self.x - other,
             y: self.y - other,
         }
     }
-    pub fn div(self: &Self, other: f32) -> Self {
+    pub fn div(self: &Self, other: N) -> Self {
         Self {
             x: self.x / other,
             y: self.y / other,
         }
     }
-    pub fn mul(self: &Self, other: f32) -> Self {
+    pub fn mul(self: &Self, other: N) -> Self {
         Self {
             x: self.x * other,
             y: self.y * other,
         }
     }
+    pub fn to_float(&self) -> Vec2<N::Float> {
+        Vec2::new(self.x.to_float(), self.y.to_float())
+    }
+    pub fn length(&self) -> N::Float {
+        let v = self.to_float();
+        (v.x * v.x + v.y * v.y).sqrt()
+    }
+}
+impl<F: Float> Vec2<F> {
+    pub fn ceil(self: Self) -> Self {
+        Self {
+            x: self.x.ceil(),
+            y: self.y.ceil(),
+        }
+    }
 }
-impl Add for Vec2 {
-    type Output = Vec2;
-    fn add(self, other: Vec2) -> Vec2 {
+impl<N: Scalar> Add for Vec2<N> {
+    type Output = Vec2<N>;
+    fn add(self, other: Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x + other.x,
             y: self.y + other.y,
@@ -50,10 +62,10 @@ impl Add for Vec2 {
     }
 }
 
-// Vec2 + &Vec2
-impl<'a> Add<&'a Vec2> for Vec2 {
-    type Output = Vec2;
-    fn add(self, other: &Vec2) -> Vec2 {
+// Vec2<N> + &Vec2<N>
+impl<'a, N: Scalar> Add<&'a Vec2<N>> for Vec2<N> {
+    type Output = Vec2<N>;
+    fn add(self, other: &Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x + other.x,
             y: self.y + other.y,
@@ -61,10 +73,10 @@ impl<'a> Add<&'a Vec2> for Vec2 {
     }
 }
 
-// &Vec2 + Vec2
-impl<'a> Add<Vec2> for &'a Vec2 {
-    type Output = Vec2;
-    fn add(self, other: Vec2) -> Vec2 {
+// &Vec2<N> + Vec2<N>
+impl<'a, N: Scalar> Add<Vec2<N>> for &'a Vec2<N> {
+    type Output = Vec2<N>;
+    fn add(self, other: Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x + other.x,
             y: self.y + other.y,
@@ -72,10 +84,10 @@ impl<'a> Add<Vec2> for &'a Vec2 {
     }
 }
 
-// &Vec2 + &Vec2 -> Vec2
-impl<'a, 'b> Add<&'b Vec2> for &'a Vec2 {
-    type Output = Vec2;
-    fn add(self, other: &Vec2) -> Vec2 {
+// &Vec2<N> + &Vec2<N> -> Vec2<N>
+impl<'a, 'b, N: Scalar> Add<&'b Vec2<N>> for &'a Vec2<N> {
+    type Output = Vec2<N>;
+    fn add(self, other: &Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x + other.x,
             y: self.y + other.y,
@@ -83,31 +95,31 @@ impl<'a, 'b> Add<&'b Vec2> for &'a Vec2 {
     }
 }
 
-impl Add<f32> for Vec2 {
-    type Output = Vec2;
-    fn add(self, rhs: f32) -> Vec2 {
+impl<N: Scalar> Add<N> for Vec2<N> {
+    type Output = Vec2<N>;
+    fn add(self, rhs: N) -> Vec2<N> {
         Vec2::new(self.x + rhs, self.y + rhs)
     }
 }
 
-impl Add<f32> for &Vec2 {
-    type Output = Vec2;
-    fn add(self, rhs: f32) -> Vec2 {
+impl<N: Scalar> Add<N> for &Vec2<N> {
+    type Output = Vec2<N>;
+    fn add(self, rhs: N) -> Vec2<N> {
         Vec2::new(self.x + rhs, self.y + rhs)
     }
 }
 
 // AddAssign for +=
-impl AddAssign for Vec2 {
-    fn add_assign(&mut self, other: Vec2) {
+impl<N: Scalar> AddAssign for Vec2<N> {
+    fn add_assign(&mut self, other: Vec2<N>) {
         self.x += other.x;
         self.y += other.y;
     }
 }
 
-impl Sub for Vec2 {
-    type Output = Vec2;
-    fn sub(self, other: Vec2) -> Vec2 {
+impl<N: Scalar> Sub for Vec2<N> {
+    type Output = Vec2<N>;
+    fn sub(self, other: Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x - other.x,
             y: self.y - other.y,
@@ -115,10 +127,10 @@ impl Sub for Vec2 {
     }
 }
 
-// Vec2 - &Vec2
-impl<'a> Sub<&'a Vec2> for Vec2 {
-    type Output = Vec2;
-    fn sub(self, other: &Vec2) -> Vec2 {
+// Vec2<N> - &Vec2<N>
+impl<'a, N: Scalar> Sub<&'a Vec2<N>> for Vec2<N> {
+    type Output = Vec2<N>;
+    fn sub(self, other: &Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x - other.x,
             y: self.y - other.y,
@@ -126,10 +138,10 @@ impl<'a> Sub<&'a Vec2> for Vec2 {
     }
 }
 
-// &Vec2 - Vec2
-impl<'a> Sub<Vec2> for &'a Vec2 {
-    type Output = Vec2;
-    fn sub(self, other: Vec2) -> Vec2 {
+// &Vec2<N> - Vec2<N>
+impl<'a, N: Scalar> Sub<Vec2<N>> for &'a Vec2<N> {
+    type Output = Vec2<N>;
+    fn sub(self, other: Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x - other.x,
             y: self.y - other.y,
@@ -137,10 +149,10 @@ impl<'a> Sub<Vec2> for &'a Vec2 {
     }
 }
 
-// &Vec2 - &Vec2 -> Vec2
-impl<'a, 'b> Sub<&'b Vec2> for &'a Vec2 {
-    type Output = Vec2;
-    fn sub(self, other: &Vec2) -> Vec2 {
+// &Vec2<N> - &Vec2<N> -> Vec2<N>
+impl<'a, 'b, N: Scalar> Sub<&'b Vec2<N>> for &'a Vec2<N> {
+    type Output = Vec2<N>;
+    fn sub(self, other: &Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x - other.x,
             y: self.y - other.y,
@@ -148,31 +160,31 @@ impl<'a, 'b> Sub<&'b Vec2> for &'a Vec2 {
     }
 }
 
-impl Sub<f32> for Vec2 {
-    type Output = Vec2;
-    fn sub(self, rhs: f32) -> Vec2 {
+impl<N: Scalar> Sub<N> for Vec2<N> {
+    type Output = Vec2<N>;
+    fn sub(self, rhs: N) -> Vec2<N> {
         Vec2::new(self.x - rhs, self.y - rhs)
     }
 }
 
-impl Sub<f32> for &Vec2 {
-    type Output = Vec2;
-    fn sub(self, rhs: f32) -> Vec2 {
+impl<N: Scalar> Sub<N> for &Vec2<N> {
+    type Output = Vec2<N>;
+    fn sub(self, rhs: N) -> Vec2<N> {
         Vec2::new(self.x - rhs, self.y - rhs)
     }
 }
 
 // SubAssign for -=
-impl SubAssign for Vec2 {
-    fn sub_assign(&mut self, other: Vec2) {
+impl<N: Scalar> SubAssign for Vec2<N> {
+    fn sub_assign(&mut self, other: Vec2<N>) {
         self.x -= other.x;
         self.y -= other.y;
     }
 }
 
-impl Div for Vec2 {
-    type Output = Vec2;
-    fn div(self, other: Vec2) -> Vec2 {
+impl<N: Scalar> Div for Vec2<N> {
+    type Output = Vec2<N>;
+    fn div(self, other: Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x / other.x,
             y: self.y / other.y,
@@ -180,10 +192,10 @@ impl Div for Vec2 {
     }
 }
 
-// Vec2 / &Vec2
-impl<'a> Div<&'a Vec2> for Vec2 {
-    type Output = Vec2;
-    fn div(self, other: &Vec2) -> Vec2 {
+// Vec2<N> / &Vec2<N>
+impl<'a, N: Scalar> Div<&'a Vec2<N>> for Vec2<N> {
+    type Output = Vec2<N>;
+    fn div(self, other: &Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x / other.x,
             y: self.y / other.y,
@@ -191,10 +203,10 @@ impl<'a> Div<&'a Vec2> for Vec2 {
     }
 }
 
-// &Vec2 / Vec2
-impl<'a> Div<Vec2> for &'a Vec2 {
-    type Output = Vec2;
-    fn div(self, other: Vec2) -> Vec2 {
+// &Vec2<N> / Vec2<N>
+impl<'a, N: Scalar> Div<Vec2<N>> for &'a Vec2<N> {
+    type Output = Vec2<N>;
+    fn div(self, other: Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x / other.x,
             y: self.y / other.y,
@@ -202,10 +214,10 @@ impl<'a> Div<Vec2> for &'a Vec2 {
     }
 }
 
-// &Vec2 / &Vec2 -> Vec2
-impl<'a, 'b> Div<&'b Vec2> for &'a Vec2 {
-    type Output = Vec2;
-    fn div(self, other: &Vec2) -> Vec2 {
+// &Vec2<N> / &Vec2<N> -> Vec2<N>
+impl<'a, 'b, N: Scalar> Div<&'b Vec2<N>> for &'a Vec2<N> {
+    type Output = Vec2<N>;
+    fn div(self, other: &Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x / other.x,
             y: self.y / other.y,
@@ -213,31 +225,31 @@ impl<'a, 'b> Div<&'b Vec2> for &'a Vec2 {
     }
 }
 
-impl Div<f32> for Vec2 {
-    type Output = Vec2;
-    fn div(self, rhs: f32) -> Vec2 {
+impl<N: Scalar> Div<N> for Vec2<N> {
+    type Output = Vec2<N>;
+    fn div(self, rhs: N) -> Vec2<N> {
         Vec2::new(self.x / rhs, self.y / rhs)
     }
 }
 
-impl Div<f32> for &Vec2 {
-    type Output = Vec2;
-    fn div(self, rhs: f32) -> Vec2 {
+impl<N: Scalar> Div<N> for &Vec2<N> {
+    type Output = Vec2<N>;
+    fn div(self, rhs: N) -> Vec2<N> {
         Vec2::new(self.x / rhs, self.y / rhs)
     }
 }
 
 // DivAssign for /=
-impl DivAssign for Vec2 {
-    fn div_assign(&mut self, other: Vec2) {
+impl<N: Scalar> DivAssign for Vec2<N> {
+    fn div_assign(&mut self, other: Vec2<N>) {
         self.x /= other.x;
         self.y /= other.y;
     }
 }
 
-impl Mul for Vec2 {
-    type Output = Vec2;
-    fn mul(self, other: Vec2) -> Vec2 {
+impl<N: Scalar> Mul for Vec2<N> {
+    type Output = Vec2<N>;
+    fn mul(self, other: Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x * other.x,
             y: self.y * other.y,
@@ -245,10 +257,10 @@ impl Mul for Vec2 {
     }
 }
 
-// Vec2 * &Vec2
-impl<'a> Mul<&'a Vec2> for Vec2 {
-    type Output = Vec2;
-    fn mul(self, other: &Vec2) -> Vec2 {
+// Vec2<N> * &Vec2<N>
+impl<'a, N: Scalar> Mul<&'a Vec2<N>> for Vec2<N> {
+    type Output = Vec2<N>;
+    fn mul(self, other: &Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x * other.x,
             y: self.y * other.y,
@@ -256,10 +268,10 @@ impl<'a> Mul<&'a Vec2> for Vec2 {
     }
 }
 
-// &Vec2 * Vec2
-impl<'a> Mul<Vec2> for &'a Vec2 {
-    type Output = Vec2;
-    fn mul(self, other: Vec2) -> Vec2 {
+// &Vec2<N> * Vec2<N>
+impl<'a, N: Scalar> Mul<Vec2<N>> for &'a Vec2<N> {
+    type Output = Vec2<N>;
+    fn mul(self, other: Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x * other.x,
             y: self.y * other.y,
@@ -267,10 +279,10 @@ impl<'a> Mul<Vec2> for &'a Vec2 {
     }
 }
 
-// &Vec2 * &Vec2 -> Vec2
-impl<'a, 'b> Mul<&'b Vec2> for &'a Vec2 {
-    type Output = Vec2;
-    fn mul(self, other: &Vec2) -> Vec2 {
+// &Vec2<N> * &Vec2<N> -> Vec2<N>
+impl<'a, 'b, N: Scalar> Mul<&'b Vec2<N>> for &'a Vec2<N> {
+    type Output = Vec2<N>;
+    fn mul(self, other: &Vec2<N>) -> Vec2<N> {
         Vec2 {
             x: self.x * other.x,
             y: self.y * other.y,
@@ -278,23 +290,23 @@ impl<'a, 'b> Mul<&'b Vec2> for &'a Vec2 {
     }
 }
 
-impl Mul<f32> for Vec2 {
-    type Output = Vec2;
-    fn mul(self, rhs: f32) -> Vec2 {
+impl<N: Scalar> Mul<N> for Vec2<N> {
+    type Output = Vec2<N>;
+    fn mul(self, rhs: N) -> Vec2<N> {
         Vec2::new(self.x * rhs, self.y * rhs)
     }
 }
 
-impl Mul<f32> for &Vec2 {
-    type Output = Vec2;
-    fn mul(self, rhs: f32) -> Vec2 {
+impl<N: Scalar> Mul<N> for &Vec2<N> {
+    type Output = Vec2<N>;
+    fn mul(self, rhs: N) -> Vec2<N> {
         Vec2::new(self.x * rhs, self.y * rhs)
     }
 }
 
 // MulAssign for *=
-impl MulAssign for Vec2 {
-    fn mul_assign(&mut self, other: Vec2) {
+impl<N: Scalar> MulAssign for Vec2<N> {
+    fn mul_assign(&mut self, other: Vec2<N>) {
         self.x *= other.x;
         self.y *= other.y;
     }
@@ -0,0 +1,186 @@
+//! Moving entities around and querying them in steady state must not touch
+//! the allocator.
+
+use spatial_hash::SpatialHash;
+use spatial_hash::concurrent::ConcurrentSpatialHash;
+use spatial_hash::query::QueryScratch;
+use spatial_hash::storage::CellStorage;
+use spatial_hash::vec2::Vec2;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+#[test]
+fn steady_state_movement_does_not_allocate() {
+    let cell_size = Vec2::new(4.0, 4.0);
+    let start = Vec2::new(0.0, 0.0);
+    let end = Vec2::new(199.0, 199.0);
+    let sparse = SpatialHash::new(cell_size.clone(), start.clone(), end.clone()).unwrap();
+    let dense = SpatialHash::dense(cell_size, start, end).unwrap();
+
+    assert_eq!(allocations_while_moving(sparse), 0);
+    assert_eq!(allocations_while_moving(dense), 0);
+}
+
+#[test]
+fn queries_into_caller_buffers_do_not_allocate() {
+    let mut grid = SpatialHash::new(
+        Vec2::new(4.0, 4.0),
+        Vec2::new(0.0, 0.0),
+        Vec2::new(199.0, 199.0),
+    )
+    .unwrap();
+    let handles: Vec<_> = (0..400)
+        .map(|i| {
+            let pos = Vec2::new((i % 20) as f32 * 9.5 + 5.0, (i / 20) as f32 * 9.5 + 5.0);
+            grid.create(pos, Vec2::new(6.0, 6.0), ()).unwrap()
+        })
+        .collect();
+    let mut scratch = QueryScratch::new();
+    let mut found = Vec::new();
+    let mut nearest = Vec::new();
+    let mut run = |handle| {
+        let pos = grid.get(handle).unwrap().pos().clone();
+        grid.find_nearest_into(handle, &mut scratch, &mut found)
+            .unwrap();
+        grid.query_rect_into(pos.sub(8.0), pos.add(8.0), &mut scratch, &mut found);
+        grid.query_radius_into(pos.clone(), 12.0, &mut scratch, &mut found);
+        grid.k_nearest_into(pos.clone(), 8, f32::INFINITY, &mut scratch, &mut nearest);
+        grid.k_nearest_into(pos, 400, f32::INFINITY, &mut scratch, &mut nearest);
+    };
+    // the first round grows the buffers to the largest answers
+    handles.iter().for_each(|&handle| run(handle));
+
+    let before = allocations();
+    for _ in 0..5 {
+        handles.iter().for_each(|&handle| run(handle));
+    }
+    assert_eq!(allocations() - before, 0);
+}
+
+#[test]
+fn queries_beside_a_running_query_do_not_allocate() {
+    let mut grid = SpatialHash::new(
+        Vec2::new(4.0, 4.0),
+        Vec2::new(0.0, 0.0),
+        Vec2::new(199.0, 199.0),
+    )
+    .unwrap();
+    for i in 0..400 {
+        let pos = Vec2::new((i % 20) as f32 * 9.5 + 5.0, (i / 20) as f32 * 9.5 + 5.0);
+        grid.create(pos, Vec2::new(6.0, 6.0), ()).unwrap();
+    }
+    let query = |grid: &SpatialHash| {
+        let near = grid
+            .query_radius_iter(Vec2::new(100.0, 100.0), 30.0)
+            .count();
+        near + grid
+            .query_rect_iter(Vec2::new(0.0, 0.0), Vec2::new(199.0, 199.0))
+            .count()
+    };
+
+    // the running query holds the stamps, so everything else falls back to
+    // the scratch of its thread, on this thread and on another one
+    let mut running = grid.query_rect_iter(Vec2::new(0.0, 0.0), Vec2::new(199.0, 199.0));
+    running.next();
+    let here = allocations_after_warm_up(|| query(&grid));
+    let there = std::thread::scope(|scope| {
+        scope
+            .spawn(|| allocations_after_warm_up(|| query(&grid)))
+            .join()
+            .unwrap()
+    });
+    assert_eq!(running.count(), 399);
+    assert_eq!((here, there), (0, 0));
+}
+
+#[test]
+fn concurrent_queries_only_allocate_their_results() {
+    let grid = ConcurrentSpatialHash::new(
+        Vec2::new(4.0, 4.0),
+        Vec2::new(0.0, 0.0),
+        Vec2::new(199.0, 199.0),
+    )
+    .unwrap();
+    for i in 0..400 {
+        let pos = Vec2::new((i % 20) as f32 * 9.5 + 5.0, (i / 20) as f32 * 9.5 + 5.0);
+        grid.create(pos, Vec2::new(6.0, 6.0), ()).unwrap();
+    }
+    // spans several cells around a single entity
+    let query = || {
+        grid.query_rect(Vec2::new(90.0, 90.0), Vec2::new(92.0, 92.0))
+            .len()
+    };
+
+    // one allocation per run for the returned `Vec`
+    assert_eq!(allocations_after_warm_up(query), 5);
+}
+
+/// Allocations of `run` on this thread, once a first run has grown the buffers.
+fn allocations_after_warm_up(mut run: impl FnMut() -> usize) -> usize {
+    assert!(run() > 0);
+    let before = allocations();
+    for _ in 0..5 {
+        assert!(run() > 0);
+    }
+    allocations() - before
+}
+
+/// Sways clustered entities back and forth and counts the allocations once
+/// the pooled buckets have grown to the largest cells they get handed to.
+fn allocations_while_moving<S: CellStorage>(mut grid: SpatialHash<(), S>) -> usize {
+    let handles: Vec<_> = (0..600)
+        .map(|i| {
+            // 20 clusters of 30, so plenty of buckets outgrow their inline storage
+            let cluster = Vec2::new((i % 20) as f32 * 9.0 + 10.0, (i % 20) as f32 * 7.0 + 30.0);
+            let offset = Vec2::new((i / 20 % 6) as f32 * 0.7, (i / 120) as f32 * 0.9);
+            grid.create(cluster + offset, Vec2::new(1.5, 1.5), ())
+                .unwrap()
+        })
+        .collect();
+
+    let mut sway = |frame: usize| {
+        let step = if frame.is_multiple_of(2) { 2.6 } else { -2.6 };
+        for (i, &handle) in handles.iter().enumerate() {
+            let delta = if i.is_multiple_of(2) { step } else { -step };
+            grid.translate(handle, Vec2::new(delta, delta * 0.5))
+                .unwrap();
+        }
+    };
+    for frame in 0..8 {
+        sway(frame);
+    }
+    let before = allocations();
+    for frame in 8..40 {
+        sway(frame);
+    }
+    allocations() - before
+}